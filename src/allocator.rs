@@ -1,4 +1,7 @@
-use linked_list_allocator::LockedHeap;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -9,10 +12,26 @@ use x86_64::{
 /// Start of heap
 pub const HEAP_START: usize = 0x4444_4444_0000;
 /// Size of heap
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB FIXME: Automatically determine an appropriate size, or dynamically grow the heap
+pub const HEAP_SIZE: usize = 512 * 1024; // 512 KiB, thread stacks live here FIXME: Automatically determine an appropriate size, or dynamically grow the heap
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Locked<Heap> = Locked::new(Heap::empty());
+
+/// The heap is only locked with interrupts disabled: a kernel thread preempted while holding the
+/// lock would otherwise deadlock every allocation made from an interrupt-free context.
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            self.lock()
+                .allocate_first_fit(layout)
+                .map_or(ptr::null_mut(), NonNull::as_ptr)
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.lock().deallocate(NonNull::new_unchecked(ptr), layout))
+    }
+}
 
 /// Implements a simple bump allocator
 pub mod bump;
@@ -62,7 +81,7 @@ impl<A> Locked<A> {
     }
 
     /// Lock inner mutex
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
         self.inner.lock()
     }
 }
@@ -0,0 +1,174 @@
+use crate::memory;
+use crate::symbols;
+use core::arch::asm;
+use core::fmt;
+use x86_64::VirtAddr;
+
+/// Maximum number of return addresses recorded in a backtrace
+const MAX_FRAMES: usize = 32;
+
+/// Return addresses of a call stack, recovered by walking the chain of saved frame pointers.
+///
+/// This relies on the kernel being built with frame pointers forced on (see `.cargo/config.toml`).
+/// Capturing never allocates, so backtraces can be taken from panic and exception handlers.
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+    /// Whether frame addresses could be checked against the page tables
+    checked: bool,
+    /// Whether the first frame is the faulting instruction itself rather than a return address
+    exact_first: bool,
+}
+
+impl Backtrace {
+    /// Capture the call stack of the caller.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        Self::from_frame_pointer(rbp)
+    }
+
+    /// Capture the call stack of an interrupted context, with the faulting instruction as first frame.
+    pub fn from_fault(instruction_pointer: VirtAddr, rbp: u64) -> Self {
+        let mut backtrace = Self::empty();
+        backtrace.push(instruction_pointer.as_u64());
+        backtrace.exact_first = true;
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /// Capture the call stack starting at the frame pointed to by `rbp`.
+    pub fn from_frame_pointer(rbp: u64) -> Self {
+        let mut backtrace = Self::empty();
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /// Recorded return addresses, innermost first
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    /// Function and offset of the frame at `index`, if the symbol table knows it
+    pub fn symbol(&self, index: usize) -> Option<symbols::Symbol> {
+        let address = *self.frames().get(index)?;
+        if index == 0 && self.exact_first {
+            return symbols::resolve(address);
+        }
+        // a return address points after the call, which may already be past the end of the
+        // calling function, so look up the call instruction instead
+        let symbol = symbols::resolve(address - 1)?;
+        Some(symbols::Symbol {
+            offset: symbol.offset + 1,
+            ..symbol
+        })
+    }
+
+    fn empty() -> Self {
+        Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            checked: true,
+            exact_first: false,
+        }
+    }
+
+    fn push(&mut self, address: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.frames[self.len] = address;
+        self.len += 1;
+        true
+    }
+
+    /// Follow the frame pointer chain. Each frame holds the caller's `rbp` at `[rbp]` and the
+    /// return address at `[rbp + 8]`.
+    ///
+    /// The walk stops at the first frame that is misaligned, unmapped or not above the previous one,
+    /// since the stack grows downwards and a corrupted chain must not fault.
+    fn walk(&mut self, mut rbp: u64) {
+        loop {
+            if rbp == 0 || !rbp.is_multiple_of(8) {
+                return;
+            }
+            match frame_is_mapped(rbp) {
+                Some(true) => {}
+                Some(false) => return,
+                None => {
+                    self.checked = false;
+                    return;
+                }
+            }
+
+            let frame = rbp as *const u64;
+            let (next, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+            if return_address == 0 || !self.push(return_address) || next <= rbp {
+                return;
+            }
+            rbp = next;
+        }
+    }
+}
+
+/// Whether both words of the frame at `rbp` can be read without faulting
+fn frame_is_mapped(rbp: u64) -> Option<bool> {
+    let start = match VirtAddr::try_new(rbp) {
+        Ok(start) => start,
+        Err(_) => return Some(false),
+    };
+    let end = match rbp.checked_add(15).map(VirtAddr::try_new) {
+        Some(Ok(end)) => end,
+        _ => return Some(false),
+    };
+
+    Some(memory::is_mapped(start)? && memory::is_mapped(end)?)
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, &address) in self.frames().iter().enumerate() {
+            write!(f, "  {:2}: {:#018x}", i, address)?;
+            match self.symbol(i) {
+                Some(symbol) => writeln!(f, " {}", symbol)?,
+                None => writeln!(f)?,
+            }
+        }
+        if !self.checked {
+            writeln!(f, "  <memory not initialized, stopping walk>")?;
+        }
+        Ok(())
+    }
+}
+
+/// Print a backtrace of the caller to the vga text buffer and the serial interface
+pub fn print() {
+    let backtrace = Backtrace::capture();
+    crate::println!("{}", backtrace);
+    crate::serial_println!("{}", backtrace);
+}
+
+/// A backtrace of the test runner contains at least the test itself
+#[test_case]
+fn test_capture() {
+    let backtrace = Backtrace::capture();
+    assert!(!backtrace.frames().is_empty());
+}
+
+/// A corrupted frame pointer ends the walk instead of faulting
+#[test_case]
+fn test_corrupted_frame_pointer() {
+    assert!(Backtrace::from_frame_pointer(0x1234_5677)
+        .frames()
+        .is_empty());
+    assert!(Backtrace::from_frame_pointer(0x8000_0000_0000_0000)
+        .frames()
+        .is_empty());
+    assert!(Backtrace::from_frame_pointer(0xdead_0000_0000)
+        .frames()
+        .is_empty());
+}
@@ -8,12 +8,12 @@ use x86_64::VirtAddr;
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
+        CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
@@ -23,11 +23,20 @@ lazy_static! {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
         let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        #[cfg(test)]
+        let absent_data_selector = {
+            use x86_64::structures::gdt::DescriptorFlags;
+
+            let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::WRITABLE;
+            gdt.add_entry(Descriptor::UserSegment(flags.bits()))
+        };
         (
             gdt,
             Selectors {
                 code_selector,
                 tss_selector,
+                #[cfg(test)]
+                absent_data_selector,
             },
         )
     };
@@ -36,6 +45,72 @@ lazy_static! {
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    /// Data segment without the present bit, loading it raises #NP
+    #[cfg(test)]
+    absent_data_selector: SegmentSelector,
+}
+
+/// Selector of a data segment that is not present, for tests
+#[cfg(test)]
+pub fn absent_data_selector() -> SegmentSelector {
+    GDT.1.absent_data_selector
+}
+
+/// Run `f` with a task register too short to hold the interrupt stack table, so that interrupts
+/// through a gate with a stack index raise #TS. Interrupts are disabled meanwhile.
+#[cfg(test)]
+pub fn with_short_tss<R>(f: impl FnOnce() -> R) -> R {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    // offset of the last byte, interrupt_stack_table[0] starts at 36
+    const SHORT_LIMIT: u64 = 35;
+
+    let (low, high) = match Descriptor::tss_segment(&TSS) {
+        Descriptor::SystemSegment(low, high) => (low, high),
+        Descriptor::UserSegment(_) => unreachable!(),
+    };
+    let short_low = (low & !0x000f_0000_0000_ffff) | SHORT_LIMIT;
+    without_interrupts(|| {
+        // ltr refuses the descriptor it marked busy, so the task register is loaded from copies
+        // of the GDT. The descriptor of the GDT itself stays busy, which it is once reloaded.
+        let mut table = [0; 8];
+        unsafe { load_tss_from_copy(&mut table, short_low, high) };
+        let result = f();
+        unsafe {
+            load_tss_from_copy(&mut table, low, high);
+            GDT.0.load();
+        }
+        result
+    })
+}
+
+/// Load `table` as GDT with the code segment and the given TSS descriptor at their usual
+/// selectors, then load the task register from it. `table` must outlive its use by the CPU.
+#[cfg(test)]
+unsafe fn load_tss_from_copy(table: &mut [u64; 8], tss_low: u64, tss_high: u64) {
+    use core::arch::asm;
+    use x86_64::instructions::tables::load_tss;
+
+    #[repr(C, packed)]
+    struct Pointer {
+        limit: u16,
+        base: u64,
+    }
+
+    let code = match Descriptor::kernel_code_segment() {
+        Descriptor::UserSegment(value) => value,
+        Descriptor::SystemSegment(..) => unreachable!(),
+    };
+    let tss_index = GDT.1.tss_selector.index() as usize;
+    table[GDT.1.code_selector.index() as usize] = code;
+    table[tss_index] = tss_low;
+    table[tss_index + 1] = tss_high;
+    let pointer = Pointer {
+        limit: (core::mem::size_of_val(table) - 1) as u16,
+        base: table.as_ptr() as u64,
+    };
+    asm!("lgdt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+    load_tss(GDT.1.tss_selector);
 }
 
 lazy_static! {
@@ -45,7 +120,7 @@ lazy_static! {
             const STACK_SIZE: usize = 4096 * 5;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
             stack_start + STACK_SIZE // Stack end
         };
         tss
@@ -1,11 +1,69 @@
 #![allow(missing_docs)]
 
-use crate::gdt;
-use crate::print;
-use crate::println;
+use crate::percpu;
+use core::fmt;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue};
+
+/// Generate the common entry code for interrupt stubs: save all general-purpose registers,
+/// hand the resulting `InterruptFrame` to `$dispatch`, then restore them and return from the
+/// interrupt.
+///
+/// The stubs must push an error code (or 0) and the vector before jumping here. The CPU aligns
+/// the stack to 16 bytes before pushing its frame, and these 17 quad words keep that alignment
+/// for the call.
+macro_rules! interrupt_entry {
+    ($name:ident, $dispatch:path) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            core::arch::naked_asm!(
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov rdi, rsp",
+                "cld",
+                "call {dispatch}",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                // drop vector and error code
+                "add rsp, 16",
+                "iretq",
+                dispatch = sym $dispatch,
+            );
+        }
+    };
+}
+
+/// Handlers for CPU exceptions
+pub mod exceptions;
+/// Runtime registration of interrupt handlers
+pub mod irq;
 
 /// Offset 1 of programmable interrupt timer
 pub const PIC_1_OFFSET: u8 = 32;
@@ -28,27 +86,76 @@ impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
     }
+}
 
-    //noinspection RsSelfConvention
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+/// General-purpose registers at the time of the interrupt, in the order the entry code pushes them.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registers = [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("r8 ", self.r8),
+            ("r9 ", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+        ];
+
+        for (i, (name, value)) in registers.iter().enumerate() {
+            write!(f, "{}={:#018x}", name, value)?;
+            if i % 3 == 2 {
+                writeln!(f)?;
+            } else {
+                write!(f, " ")?;
+            }
+        }
+        Ok(())
     }
 }
 
+/// Everything saved on the stack between the interrupt and the call into its dispatch function.
+#[repr(C)]
+pub struct InterruptFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    /// Error code pushed by the CPU, or 0 for interrupts without one
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        exceptions::set_handlers(&mut idt);
+        irq::set_handlers(&mut idt);
 
         idt
     };
@@ -56,53 +163,28 @@ lazy_static! {
 
 pub fn init_idt() {
     IDT.load();
-}
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: x86_64::structures::idt::PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Acessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    irq::register(InterruptIndex::Timer.as_u8(), timer_interrupt_handler)
+        .expect("failed to register timer handler");
+    irq::register(InterruptIndex::Keyboard.as_u8(), keyboard_interrupt_handler)
+        .expect("failed to register keyboard handler");
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt_handler() {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    println!("EXCEPTION: DOUBLE FAULT\n{:#?}\n", stack_frame);
-    panic!("Double fault");
+/// Count a timer tick: advance the clock, expire timers and preempt threads. Called by the
+/// interrupt of whichever device drives the timer, see `time::rtc::start_timer`.
+pub(crate) fn timer_interrupt_handler() {
+    percpu::current().record_timer_tick();
+    crate::time::tick();
+    crate::task::timer::expire_timers();
+    crate::thread::tick();
 }
 
 #[test_case]
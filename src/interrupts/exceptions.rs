@@ -0,0 +1,525 @@
+use super::InterruptFrame;
+use crate::backtrace::Backtrace;
+use crate::gdt;
+use crate::println;
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, SelectorErrorCode};
+use x86_64::VirtAddr;
+
+/// Architectural exceptions that get a handler in the IDT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    ControlProtection = 21,
+    HypervisorInjection = 28,
+    VmmCommunication = 29,
+    Security = 30,
+}
+
+impl Exception {
+    /// Exception raised on the given interrupt vector, if it is one we handle.
+    pub fn from_vector(vector: u64) -> Option<Self> {
+        use Exception::*;
+
+        Some(match vector {
+            0 => DivideError,
+            1 => Debug,
+            2 => NonMaskableInterrupt,
+            3 => Breakpoint,
+            4 => Overflow,
+            5 => BoundRangeExceeded,
+            6 => InvalidOpcode,
+            7 => DeviceNotAvailable,
+            8 => DoubleFault,
+            10 => InvalidTss,
+            11 => SegmentNotPresent,
+            12 => StackSegmentFault,
+            13 => GeneralProtectionFault,
+            14 => PageFault,
+            16 => X87FloatingPoint,
+            17 => AlignmentCheck,
+            18 => MachineCheck,
+            19 => SimdFloatingPoint,
+            20 => Virtualization,
+            21 => ControlProtection,
+            28 => HypervisorInjection,
+            29 => VmmCommunication,
+            30 => Security,
+            _ => return None,
+        })
+    }
+
+    /// Human readable name, as printed in the exception banner
+    pub fn name(self) -> &'static str {
+        use Exception::*;
+
+        match self {
+            DivideError => "DIVIDE ERROR",
+            Debug => "DEBUG",
+            NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+            Breakpoint => "BREAKPOINT",
+            Overflow => "OVERFLOW",
+            BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            InvalidOpcode => "INVALID OPCODE",
+            DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            DoubleFault => "DOUBLE FAULT",
+            InvalidTss => "INVALID TSS",
+            SegmentNotPresent => "SEGMENT NOT PRESENT",
+            StackSegmentFault => "STACK SEGMENT FAULT",
+            GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            PageFault => "PAGE FAULT",
+            X87FloatingPoint => "X87 FLOATING POINT",
+            AlignmentCheck => "ALIGNMENT CHECK",
+            MachineCheck => "MACHINE CHECK",
+            SimdFloatingPoint => "SIMD FLOATING POINT",
+            Virtualization => "VIRTUALIZATION",
+            ControlProtection => "CONTROL PROTECTION",
+            HypervisorInjection => "HYPERVISOR INJECTION",
+            VmmCommunication => "VMM COMMUNICATION",
+            Security => "SECURITY",
+        }
+    }
+
+    /// Mnemonic used by the Intel and AMD manuals
+    pub fn mnemonic(self) -> &'static str {
+        use Exception::*;
+
+        match self {
+            DivideError => "#DE",
+            Debug => "#DB",
+            NonMaskableInterrupt => "NMI",
+            Breakpoint => "#BP",
+            Overflow => "#OF",
+            BoundRangeExceeded => "#BR",
+            InvalidOpcode => "#UD",
+            DeviceNotAvailable => "#NM",
+            DoubleFault => "#DF",
+            InvalidTss => "#TS",
+            SegmentNotPresent => "#NP",
+            StackSegmentFault => "#SS",
+            GeneralProtectionFault => "#GP",
+            PageFault => "#PF",
+            X87FloatingPoint => "#MF",
+            AlignmentCheck => "#AC",
+            MachineCheck => "#MC",
+            SimdFloatingPoint => "#XM",
+            Virtualization => "#VE",
+            ControlProtection => "#CP",
+            HypervisorInjection => "#HV",
+            VmmCommunication => "#VC",
+            Security => "#SX",
+        }
+    }
+
+    /// Aborts leave the machine in an undefined state, and can never be resumed.
+    fn is_abort(self) -> bool {
+        matches!(self, Exception::DoubleFault | Exception::MachineCheck)
+    }
+}
+
+/// Address to resume at after the next recoverable exception, or 0 if it should not be recovered
+static FIXUP: AtomicU64 = AtomicU64::new(0);
+
+/// Vector of the last exception recovered through `FIXUP`, or `NO_VECTOR`
+static FIXED_VECTOR: AtomicU64 = AtomicU64::new(NO_VECTOR);
+
+const NO_VECTOR: u64 = u64::MAX;
+
+/// Generate an entry stub that pushes the vector (and a zero error code if the CPU pushes none),
+/// then jumps to the common register-saving code.
+macro_rules! exception_stub {
+    ($name:ident, $vector:expr) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            );
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            );
+        }
+    };
+}
+
+exception_stub!(divide_error_stub, 0);
+exception_stub!(debug_stub, 1);
+exception_stub!(non_maskable_interrupt_stub, 2);
+exception_stub!(breakpoint_stub, 3);
+exception_stub!(overflow_stub, 4);
+exception_stub!(bound_range_exceeded_stub, 5);
+exception_stub!(invalid_opcode_stub, 6);
+exception_stub!(device_not_available_stub, 7);
+exception_stub!(double_fault_stub, 8, error_code);
+exception_stub!(invalid_tss_stub, 10, error_code);
+exception_stub!(segment_not_present_stub, 11, error_code);
+exception_stub!(stack_segment_fault_stub, 12, error_code);
+exception_stub!(general_protection_fault_stub, 13, error_code);
+exception_stub!(page_fault_stub, 14, error_code);
+exception_stub!(x87_floating_point_stub, 16);
+exception_stub!(alignment_check_stub, 17, error_code);
+exception_stub!(machine_check_stub, 18);
+exception_stub!(simd_floating_point_stub, 19);
+exception_stub!(virtualization_stub, 20);
+exception_stub!(control_protection_stub, 21, error_code);
+exception_stub!(hypervisor_injection_stub, 28);
+exception_stub!(vmm_communication_stub, 29, error_code);
+exception_stub!(security_stub, 30, error_code);
+
+interrupt_entry!(exception_common, exception_dispatch);
+
+/// Point every exception entry of the IDT at its entry stub.
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    fn addr(stub: extern "C" fn()) -> VirtAddr {
+        VirtAddr::new(stub as usize as u64)
+    }
+
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(divide_error_stub));
+        idt.debug.set_handler_addr(addr(debug_stub));
+        idt.non_maskable_interrupt
+            .set_handler_addr(addr(non_maskable_interrupt_stub));
+        idt.breakpoint.set_handler_addr(addr(breakpoint_stub));
+        // into is invalid in 64-bit mode, so #OF can't be raised from ring 0 here
+        idt.overflow.set_handler_addr(addr(overflow_stub));
+        // same for bound and #BR
+        idt.bound_range_exceeded
+            .set_handler_addr(addr(bound_range_exceeded_stub));
+        idt.invalid_opcode
+            .set_handler_addr(addr(invalid_opcode_stub));
+        idt.device_not_available
+            .set_handler_addr(addr(device_not_available_stub));
+        idt.double_fault
+            .set_handler_addr(addr(double_fault_stub))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(addr(invalid_tss_stub));
+        idt.segment_not_present
+            .set_handler_addr(addr(segment_not_present_stub));
+        idt.stack_segment_fault
+            .set_handler_addr(addr(stack_segment_fault_stub));
+        idt.general_protection_fault
+            .set_handler_addr(addr(general_protection_fault_stub));
+        idt.page_fault.set_handler_addr(addr(page_fault_stub));
+        idt.x87_floating_point
+            .set_handler_addr(addr(x87_floating_point_stub));
+        // alignment checks only apply at CPL 3, #AC can't be raised from ring 0
+        idt.alignment_check
+            .set_handler_addr(addr(alignment_check_stub));
+        idt.machine_check.set_handler_addr(addr(machine_check_stub));
+        idt.simd_floating_point
+            .set_handler_addr(addr(simd_floating_point_stub));
+        idt.virtualization
+            .set_handler_addr(addr(virtualization_stub));
+        idt.cp_protection_exception
+            .set_handler_addr(addr(control_protection_stub));
+        idt.hv_injection_exception
+            .set_handler_addr(addr(hypervisor_injection_stub));
+        idt.vmm_communication_exception
+            .set_handler_addr(addr(vmm_communication_stub));
+        idt.security_exception.set_handler_addr(addr(security_stub));
+    }
+}
+
+/// Called by `exception_common` for every exception, with interrupts disabled.
+extern "C" fn exception_dispatch(frame: &mut InterruptFrame) {
+    let exception = match Exception::from_vector(frame.vector) {
+        Some(exception) => exception,
+        None => panic!("exception stub for unknown vector {}", frame.vector),
+    };
+
+    println!("EXCEPTION: {} ({})", exception.name(), exception.mnemonic());
+    print_error_code(exception, frame.error_code);
+    println!("{:#?}", frame.stack_frame);
+    println!("{}", frame.registers);
+
+    if !exception.is_abort() {
+        let resume = FIXUP.swap(0, Ordering::Relaxed);
+        if resume != 0 {
+            FIXED_VECTOR.store(frame.vector, Ordering::Relaxed);
+            frame.stack_frame.instruction_pointer = VirtAddr::new(resume);
+            return;
+        }
+    }
+
+    match exception {
+        // traps and interrupts: execution continues after the instruction that raised them
+        Exception::Breakpoint | Exception::Debug | Exception::NonMaskableInterrupt => {}
+        Exception::PageFault => {
+            print_fault_backtrace(frame);
+            crate::hlt_loop()
+        }
+        Exception::DoubleFault => {
+            print_fault_backtrace(frame);
+            panic!("Double fault")
+        }
+        _ => panic!("Unrecoverable {} exception", exception.mnemonic()),
+    }
+}
+
+/// Print the call stack of the interrupted code, starting at the faulting instruction.
+fn print_fault_backtrace(frame: &InterruptFrame) {
+    let backtrace =
+        Backtrace::from_fault(frame.stack_frame.instruction_pointer, frame.registers.rbp);
+    println!("{}", backtrace);
+}
+
+/// Print the error code pushed by the CPU, decoded according to the exception that pushed it.
+fn print_error_code(exception: Exception, error_code: u64) {
+    use x86_64::registers::control::Cr2;
+
+    match exception {
+        Exception::InvalidTss
+        | Exception::SegmentNotPresent
+        | Exception::StackSegmentFault
+        | Exception::GeneralProtectionFault => {
+            let selector = SelectorErrorCode::new_truncate(error_code);
+            if selector.is_null() {
+                println!("Error Code: none (not segment related)");
+            } else {
+                println!("Error Code: {:?}", selector);
+            }
+        }
+        Exception::PageFault => {
+            println!("Acessed Address: {:?}", Cr2::read());
+            println!(
+                "Error Code: {:?}",
+                PageFaultErrorCode::from_bits_truncate(error_code)
+            );
+        }
+        Exception::ControlProtection => {
+            let enclave = if error_code & CP_ERROR_ENCLAVE != 0 {
+                ", in an enclave"
+            } else {
+                ""
+            };
+            println!(
+                "Error Code: {:#x} ({}{})",
+                error_code,
+                control_protection_cause(error_code),
+                enclave
+            );
+        }
+        Exception::VmmCommunication | Exception::Security => {
+            println!("Error Code: {:#x}", error_code);
+        }
+        // #DF and #AC always push 0, the others push nothing
+        _ => {}
+    }
+}
+
+/// Set in the #CP error code when the violation happened inside an SGX enclave
+const CP_ERROR_ENCLAVE: u64 = 1 << 15;
+
+/// Control flow violation reported by the low bits of a #CP error code
+fn control_protection_cause(error_code: u64) -> &'static str {
+    match error_code & 0x7fff {
+        1 => "near return address mismatch",
+        2 => "far return or iret address mismatch",
+        3 => "missing endbranch",
+        4 => "invalid rstorssp token",
+        5 => "invalid setssbsy token",
+        _ => "unknown cause",
+    }
+}
+
+/// Run the given instructions, expecting them to raise a recoverable exception.
+///
+/// The exception handler resumes execution right after the instructions, and the vector of the
+/// exception (if any was raised) is returned.
+#[cfg(test)]
+macro_rules! expect_exception {
+    ($($instruction:literal),+ $(; $($operands:tt)*)?) => {{
+        FIXED_VECTOR.store(NO_VECTOR, Ordering::Relaxed);
+        unsafe {
+            core::arch::asm!(
+                "lea {resume}, [rip + 2f]",
+                "mov [{fixup}], {resume}",
+                $($instruction,)+
+                "2:",
+                fixup = in(reg) FIXUP.as_ptr(),
+                resume = out(reg) _,
+                $($($operands)*)?
+            );
+        }
+        FIXUP.store(0, Ordering::Relaxed);
+        Exception::from_vector(FIXED_VECTOR.swap(NO_VECTOR, Ordering::Relaxed))
+    }};
+}
+
+#[test_case]
+fn test_divide_error() {
+    let exception = expect_exception!(
+        "xor eax, eax",
+        "xor edx, edx",
+        "xor ecx, ecx",
+        "div ecx";
+        out("rax") _, out("rdx") _, out("rcx") _
+    );
+    assert_eq!(exception, Some(Exception::DivideError));
+}
+
+#[test_case]
+fn test_debug() {
+    // int1 (icebp) raises a debug trap
+    let exception = expect_exception!(".byte 0xf1");
+    assert_eq!(exception, Some(Exception::Debug));
+}
+
+#[test_case]
+fn test_invalid_opcode() {
+    let exception = expect_exception!("ud2");
+    assert_eq!(exception, Some(Exception::InvalidOpcode));
+}
+
+#[test_case]
+fn test_device_not_available() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    // with CR0.TS set, any x87 instruction faults
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+    let exception = expect_exception!("fninit");
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED)) };
+    assert_eq!(exception, Some(Exception::DeviceNotAvailable));
+}
+
+#[test_case]
+fn test_general_protection_fault() {
+    // non-canonical address
+    let exception = expect_exception!(
+        "mov rcx, 0x8000000000000000",
+        "mov rax, [rcx]";
+        out("rax") _, out("rcx") _
+    );
+    assert_eq!(exception, Some(Exception::GeneralProtectionFault));
+}
+
+#[test_case]
+fn test_stack_segment_fault() {
+    // non-canonical address through the stack segment
+    let exception = expect_exception!(
+        "mov rcx, 0x8000000000000000",
+        "mov rax, [rsp + rcx]";
+        out("rax") _, out("rcx") _
+    );
+    assert_eq!(exception, Some(Exception::StackSegmentFault));
+}
+
+#[test_case]
+fn test_invalid_tss() {
+    // the double fault gate has a stack index, whose entry lies beyond the short TSS
+    let exception = gdt::with_short_tss(|| expect_exception!("int 8"));
+    assert_eq!(exception, Some(Exception::InvalidTss));
+}
+
+#[test_case]
+fn test_segment_not_present() {
+    let exception = expect_exception!(
+        "mov es, {selector:x}";
+        selector = in(reg) gdt::absent_data_selector().0
+    );
+    assert_eq!(exception, Some(Exception::SegmentNotPresent));
+}
+
+#[test_case]
+fn test_x87_floating_point() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    // with CR0.NE set, unmasked x87 exceptions raise #MF at the next waiting instruction instead
+    // of asserting FERR#. The kernel is built without x87 code, no register needs saving.
+    let cr0 = Cr0::read();
+    unsafe { Cr0::write(cr0 | Cr0Flags::NUMERIC_ERROR) };
+    // default control word, with zero divide unmasked
+    let control_word: u16 = 0x037b;
+    let exception = expect_exception!(
+        "fninit",
+        "fldcw [{control_word}]",
+        "fld1",
+        "fldz",
+        "fdivp st(1), st",
+        "fwait";
+        control_word = in(reg) &control_word
+    );
+    unsafe {
+        core::arch::asm!("fninit", options(nomem, nostack));
+        Cr0::write(cr0);
+    }
+    assert_eq!(exception, Some(Exception::X87FloatingPoint));
+}
+
+#[test_case]
+fn test_simd_floating_point() {
+    use x86_64::registers::control::{Cr4, Cr4Flags};
+
+    // without CR4.OSXMMEXCPT, unmasked SIMD exceptions raise #UD instead. The kernel is built
+    // without SSE code, no register needs saving.
+    let cr4 = Cr4::read();
+    unsafe { Cr4::write(cr4 | Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE) };
+    // default MXCSR, with zero divide unmasked
+    let mxcsr: u32 = 0x1d80;
+    let exception = expect_exception!(
+        "ldmxcsr [{mxcsr}]",
+        "xorps xmm0, xmm0",
+        "mov eax, 1",
+        "cvtsi2ss xmm1, eax",
+        "divss xmm1, xmm0";
+        mxcsr = in(reg) &mxcsr,
+        out("rax") _
+    );
+    let default_mxcsr: u32 = 0x1f80;
+    unsafe {
+        core::arch::asm!("ldmxcsr [{}]", in(reg) &default_mxcsr, options(nostack));
+        Cr4::write(cr4);
+    }
+    assert_eq!(exception, Some(Exception::SimdFloatingPoint));
+}
+
+#[test_case]
+fn test_control_protection_error_code() {
+    assert_eq!(
+        Exception::from_vector(21),
+        Some(Exception::ControlProtection)
+    );
+    assert_eq!(control_protection_cause(3), "missing endbranch");
+    assert_eq!(
+        control_protection_cause(CP_ERROR_ENCLAVE | 1),
+        "near return address mismatch"
+    );
+    assert_eq!(control_protection_cause(0), "unknown cause");
+}
+
+#[test_case]
+fn test_breakpoint_resumes() {
+    let exception = expect_exception!("int3");
+    assert_eq!(exception, Some(Exception::Breakpoint));
+}
@@ -0,0 +1,371 @@
+use super::{InterruptFrame, PICS, PIC_1_OFFSET, PIC_2_OFFSET};
+use crate::percpu;
+use alloc::boxed::Box;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+/// First vector available for interrupts; the ones below are reserved for CPU exceptions
+pub const FIRST_VECTOR: u8 = 32;
+/// Number of legacy IRQ lines served by the chained PICs
+pub const IRQ_LINES: u8 = 16;
+/// Number of handlers that can share a single vector
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+const VECTORS: usize = 256 - FIRST_VECTOR as usize;
+
+/// Distance between two entry stubs, see the `global_asm!` below
+const STUB_SIZE: u64 = 16;
+
+const PRIMARY_COMMAND: u16 = 0x20;
+const SECONDARY_COMMAND: u16 = 0xA0;
+/// Lowest priority line of each PIC, which it raises for spurious interrupts
+const SPURIOUS_LINE: u8 = 7;
+
+/// A registered handler.
+///
+/// Function items and closures capturing nothing are zero-sized, so boxing them does not
+/// allocate: they can be registered before the heap is initialized.
+type Handler = Box<dyn Fn() + Send + Sync>;
+
+struct Slot {
+    id: u64,
+    handler: Handler,
+}
+
+type Slots = [Option<Slot>; MAX_SHARED_HANDLERS];
+
+/// Handlers of each vector, starting at `FIRST_VECTOR`.
+///
+/// The write lock is only taken with interrupts disabled, so interrupt handlers never spin on it.
+static HANDLERS: [RwLock<Slots>; VECTORS] =
+    [const { RwLock::new([const { None }; MAX_SHARED_HANDLERS]) }; VECTORS];
+
+/// Interrupts that arrived on a vector without any handler
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+/// Spurious interrupts raised by the PICs, which are neither handled nor acknowledged
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a registered handler, to unregister it later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    /// Vector the handler is registered for
+    pub fn vector(self) -> u8 {
+        self.vector
+    }
+}
+
+/// Reasons why a handler can't be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// Vectors below `FIRST_VECTOR` belong to CPU exceptions
+    ExceptionVector(u8),
+    /// Only IRQ lines 0 to 15 exist on the chained PICs
+    InvalidIrq(u8),
+    /// The vector already has `MAX_SHARED_HANDLERS` handlers
+    Full(u8),
+}
+
+/// Register a handler for the given vector (32 to 255).
+///
+/// Several handlers may share a vector; they are all called each time it fires. End of interrupt
+/// is signaled to the PICs automatically after the last one.
+///
+/// Handlers run with interrupts disabled, so they must not block or allocate, and must not
+/// register or unregister handlers themselves.
+pub fn register<F>(vector: u8, handler: F) -> Result<HandlerId, RegisterError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    if vector < FIRST_VECTOR {
+        return Err(RegisterError::ExceptionVector(vector));
+    }
+
+    let handler: Handler = Box::new(handler);
+    without_interrupts(|| {
+        let mut slots = HANDLERS[usize::from(vector - FIRST_VECTOR)].write();
+        let free = slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegisterError::Full(vector))?;
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        *free = Some(Slot { id, handler });
+        Ok(HandlerId { vector, id })
+    })
+}
+
+/// Register a handler for the given legacy IRQ line (0 to 15) of the chained PICs.
+///
+/// The line is unmasked on the PICs, in case the firmware left it masked.
+pub fn register_irq<F>(irq: u8, handler: F) -> Result<HandlerId, RegisterError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    if irq >= IRQ_LINES {
+        return Err(RegisterError::InvalidIrq(irq));
+    }
+    let id = register(PIC_1_OFFSET + irq, handler)?;
+    unmask(irq);
+    Ok(id)
+}
+
+/// Clear the mask bit of an IRQ line, and of the cascade line for those on the secondary PIC.
+pub(crate) fn unmask(irq: u8) {
+    set_masked(irq, false);
+}
+
+/// Set the mask bit of an IRQ line, so that the PICs no longer raise it.
+pub(crate) fn mask(irq: u8) {
+    set_masked(irq, true);
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    const PRIMARY_DATA: u16 = 0x21;
+    const SECONDARY_DATA: u16 = 0xA1;
+    const CASCADE_IRQ: u8 = 2;
+
+    let update = |port: u16, line: u8| {
+        let mut port = Port::<u8>::new(port);
+        unsafe {
+            let mask = port.read();
+            if masked {
+                port.write(mask | (1 << line));
+            } else {
+                port.write(mask & !(1 << line));
+            }
+        }
+    };
+
+    without_interrupts(|| {
+        // the masks belong to the PICs, keep others from reprogramming them meanwhile
+        let _pics = PICS.lock();
+        if irq < 8 {
+            update(PRIMARY_DATA, irq);
+        } else {
+            update(SECONDARY_DATA, irq - 8);
+            // other lines of the secondary PIC may still be in use
+            if !masked {
+                update(PRIMARY_DATA, CASCADE_IRQ);
+            }
+        }
+    });
+}
+
+/// Remove a previously registered handler.
+///
+/// Returns `false` if it was already unregistered.
+pub fn unregister(id: HandlerId) -> bool {
+    let removed = without_interrupts(|| {
+        let mut slots = HANDLERS[usize::from(id.vector - FIRST_VECTOR)].write();
+        slots
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(slot) if slot.id == id.id))
+            .and_then(Option::take)
+    });
+    // the handler is dropped here, with interrupts enabled again
+    removed.is_some()
+}
+
+/// Number of interrupts that arrived on a vector without any registered handler
+pub fn unhandled_count() -> u64 {
+    UNHANDLED.load(Ordering::Relaxed)
+}
+
+/// Number of spurious interrupts raised by the PICs
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Whether an interrupt on the lowest priority line of a PIC is spurious.
+///
+/// A PIC raises that line when the request that made it interrupt went away before the CPU
+/// acknowledged it, but then it does not set the in-service bit of the line.
+fn is_spurious(vector: u8) -> bool {
+    const READ_ISR: u8 = 0x0B;
+
+    let command = if vector == PIC_1_OFFSET + SPURIOUS_LINE {
+        PRIMARY_COMMAND
+    } else if vector == PIC_2_OFFSET + SPURIOUS_LINE {
+        SECONDARY_COMMAND
+    } else {
+        return false;
+    };
+    let _pics = PICS.lock();
+    let mut port = Port::<u8>::new(command);
+    unsafe {
+        port.write(READ_ISR);
+        port.read() & (1 << SPURIOUS_LINE) == 0
+    }
+}
+
+// One entry stub per vector, each padded to `STUB_SIZE` bytes so their address can be computed
+// from the vector. Every stub pushes a zero error code and its vector, like the exception stubs.
+global_asm!(
+    ".pushsection .text.irq_stubs, \"ax\"",
+    ".balign 16",
+    ".global irq_stubs",
+    "irq_stubs:",
+    ".set irq_vector, {first}",
+    ".rept {count}",
+    ".balign 16",
+    "pushq $0",
+    "pushq $irq_vector",
+    "jmp {common}",
+    ".set irq_vector, irq_vector + 1",
+    ".endr",
+    ".popsection",
+    first = const FIRST_VECTOR,
+    count = const VECTORS,
+    common = sym interrupt_common,
+    options(att_syntax),
+);
+
+extern "C" {
+    fn irq_stubs();
+}
+
+interrupt_entry!(interrupt_common, interrupt_dispatch);
+
+/// Point every interrupt entry of the IDT at its entry stub.
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    let stubs = irq_stubs as *const () as u64;
+    for index in 0..VECTORS {
+        let stub = VirtAddr::new(stubs + index as u64 * STUB_SIZE);
+        unsafe {
+            idt[usize::from(FIRST_VECTOR) + index].set_handler_addr(stub);
+        }
+    }
+}
+
+/// Called by `interrupt_common` for every interrupt, with interrupts disabled.
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    let vector = frame.vector as u8;
+    percpu::current().record_interrupt();
+
+    if is_spurious(vector) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        // the primary PIC did raise the cascade line for the secondary one, and waits for an
+        // end of interrupt for it
+        if vector == PIC_2_OFFSET + SPURIOUS_LINE {
+            const END_OF_INTERRUPT: u8 = 0x20;
+            let _pics = PICS.lock();
+            unsafe { Port::<u8>::new(PRIMARY_COMMAND).write(END_OF_INTERRUPT) };
+        }
+        return;
+    }
+
+    let mut handled = false;
+    for slot in HANDLERS[usize::from(vector - FIRST_VECTOR)]
+        .read()
+        .iter()
+        .flatten()
+    {
+        (slot.handler)();
+        handled = true;
+    }
+    if !handled {
+        UNHANDLED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    {
+        let mut pics = PICS.lock();
+        if pics.handles_interrupt(vector) {
+            unsafe { pics.notify_end_of_interrupt(vector) };
+        }
+    }
+
+    // may switch threads, so no lock can be held here
+    crate::thread::preempt_if_needed();
+}
+
+/// Vector not used by any device, for software interrupts in tests
+#[cfg(test)]
+const TEST_VECTOR: u8 = 0x80;
+
+#[cfg(test)]
+static TEST_CALLS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+fn raise_test_vector() {
+    unsafe { core::arch::asm!("int {}", const TEST_VECTOR) };
+}
+
+#[test_case]
+fn test_register_and_unregister() {
+    let before = TEST_CALLS.load(Ordering::Relaxed);
+    let id = register(TEST_VECTOR, || {
+        TEST_CALLS.fetch_add(1, Ordering::Relaxed);
+    })
+    .expect("register failed");
+
+    raise_test_vector();
+    assert_eq!(TEST_CALLS.load(Ordering::Relaxed), before + 1);
+
+    assert!(unregister(id));
+    assert!(!unregister(id));
+    let unhandled = unhandled_count();
+    raise_test_vector();
+    assert_eq!(TEST_CALLS.load(Ordering::Relaxed), before + 1);
+    assert_eq!(unhandled_count(), unhandled + 1);
+}
+
+#[test_case]
+fn test_shared_vector() {
+    let before = TEST_CALLS.load(Ordering::Relaxed);
+    let first = register(TEST_VECTOR, || {
+        TEST_CALLS.fetch_add(1, Ordering::Relaxed);
+    })
+    .expect("register failed");
+    let second = register(TEST_VECTOR, || {
+        TEST_CALLS.fetch_add(10, Ordering::Relaxed);
+    })
+    .expect("register failed");
+
+    raise_test_vector();
+    assert_eq!(TEST_CALLS.load(Ordering::Relaxed), before + 11);
+
+    unregister(first);
+    unregister(second);
+}
+
+#[test_case]
+fn test_spurious_irq() {
+    // nothing is in service on the PICs, so these look exactly like spurious interrupts
+    let unhandled = unhandled_count();
+    let spurious = spurious_count();
+    unsafe {
+        core::arch::asm!("int {}", const PIC_1_OFFSET + SPURIOUS_LINE);
+        core::arch::asm!("int {}", const PIC_2_OFFSET + SPURIOUS_LINE);
+    }
+    assert_eq!(spurious_count(), spurious + 2);
+    assert_eq!(unhandled_count(), unhandled);
+}
+
+#[test_case]
+fn test_register_errors() {
+    assert_eq!(register(14, || {}), Err(RegisterError::ExceptionVector(14)));
+    assert_eq!(register_irq(16, || {}), Err(RegisterError::InvalidIrq(16)));
+
+    let ids: [HandlerId; MAX_SHARED_HANDLERS] =
+        core::array::from_fn(|_| register(TEST_VECTOR, || {}).expect("register failed"));
+    assert_eq!(
+        register(TEST_VECTOR, || {}),
+        Err(RegisterError::Full(TEST_VECTOR))
+    );
+    for id in ids.iter() {
+        unregister(*id);
+    }
+}
@@ -1,7 +1,6 @@
 #![no_std]
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
-#![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
@@ -10,10 +9,13 @@
 //! rust_os Library
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::BootInfo;
 
+/// Keeps the entry point, which is public, out of the documented items
 #[cfg(test)]
-entry_point!(test_kernel_main);
+mod test_entry {
+    bootloader::entry_point!(super::test_kernel_main);
+}
 
 use core::panic::PanicInfo;
 
@@ -21,16 +23,26 @@ extern crate alloc;
 
 /// Memory allocations
 pub mod allocator;
+/// Frame pointer based stack backtraces
+pub mod backtrace;
 /// Global Descriptor Table
 pub mod gdt;
 /// Interrupts
 pub mod interrupts;
 /// Memory managment
 pub mod memory;
+/// Per-CPU data
+pub mod percpu;
 /// Serial io
 pub mod serial;
+/// Kernel symbol table, read from the kernel image to print `function+offset` in backtraces
+pub mod symbols;
 /// Task struct for async stuff
 pub mod task;
+/// Preemptive kernel threads
+pub mod thread;
+/// Monotonic clock and timer hardware
+pub mod time;
 pub mod vga_buffer;
 
 /// Halt the cpu in a loop, never returns.
@@ -48,13 +60,18 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 /// Initialize hardware and software
 pub fn init() {
     gdt::init();
+    percpu::init();
     interrupts::init_idt();
     unsafe {
         interrupts::PICS.lock().initialize();
     };
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
+/// Name of the running test, to find its frame in failure reports
+static RUNNING_TEST: spin::Mutex<Option<&'static str>> = spin::Mutex::new(None);
+
 /// This trait marks a function as testable. It is used for testing
 pub trait Testable {
     /// Runnable function
@@ -66,8 +83,11 @@ where
     T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        let name = core::any::type_name::<T>();
+        serial_print!("{}...\t", name);
+        *RUNNING_TEST.lock() = Some(name);
         self();
+        *RUNNING_TEST.lock() = None;
         serial_println!("[ok]");
     }
 }
@@ -89,19 +109,45 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 /// It also exits qemu with failure
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]");
+    if let Some(location) = failed_test_location() {
+        serial_println!("in {}", location);
+    }
     println!("{}", info);
     serial_println!("{}", info);
+    backtrace::print();
     exit_qemu(QemuExitCode::Failed);
 
     hlt_loop();
 }
 
+/// Function and offset in the running test at which it panicked, or called the code that did
+fn failed_test_location() -> Option<symbols::Symbol> {
+    let test = (*RUNNING_TEST.try_lock()?)?;
+    let backtrace = backtrace::Backtrace::capture();
+    (0..backtrace.frames().len())
+        .filter_map(|index| backtrace.symbol(index))
+        .find(|symbol| symbol.is(test))
+}
+
 //noinspection RsUnresolvedReference
 // needed because of test_main
 /// Entry point for tests
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootinfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     init();
+    // backtraces check that frames are mapped before reading them
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    symbols::init(boot_info);
+    let mut frame_allocator = unsafe { BootinfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+    // tests of the HPET skip themselves if it is missing
+    let _ = time::hpet::init(&mut mapper, &mut frame_allocator);
+
     test_main();
 
     hlt_loop();
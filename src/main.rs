@@ -16,7 +16,8 @@ use rust_os::println;
 #[allow(unused_imports)] // needed for tests
 use rust_os::serial_println;
 use rust_os::task::keyboard;
-use rust_os::task::{executor::Executor, Task};
+use rust_os::task::{executor::Executor, Priority, Task};
+use rust_os::thread;
 use x86_64::VirtAddr;
 
 mod undoc {
@@ -33,25 +34,40 @@ mod undoc {
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use rust_os::allocator;
     use rust_os::memory;
+    use rust_os::time;
 
     println!("Hello World!");
     rust_os::init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    rust_os::symbols::init(boot_info);
     let mut frame_allocator = unsafe { BootinfoFrameAllocator::init(&boot_info.memory_map) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+    if let Err(error) = time::hpet::init(&mut mapper, &mut frame_allocator) {
+        println!("HPET unavailable: {:?}", error);
+    }
+    if !time::set_tickless(true) {
+        println!("No free running clock, keeping the periodic timer while idle");
+    }
 
     #[cfg(test)]
     test_main();
 
     println!("Kernel initialized, starting task loop");
 
-    let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
-    executor.run();
+    let executor = thread::spawn_thread(|| {
+        let mut executor = Executor::new();
+        executor.spawn_task(Task::named("example", example_task()));
+        executor.spawn_task(
+            Task::with_priority(keyboard::print_keypresses(), Priority::High).with_name("keyboard"),
+        );
+        executor.run()
+    });
+    executor.join();
+    unreachable!("the executor never returns");
 }
 
 async fn async_number() -> u32 {
@@ -71,6 +87,7 @@ async fn example_task() {
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
     serial_println!("{}", info);
+    rust_os::backtrace::print();
 
     rust_os::hlt_loop();
 }
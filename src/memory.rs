@@ -4,6 +4,10 @@ use x86_64::structures::paging::{
 use x86_64::{PhysAddr, VirtAddr};
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Offset at which the complete physical memory is mapped, or 0 before `init` was called.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootinfoFrameAllocator {
@@ -58,6 +62,7 @@ unsafe impl FrameAllocator<Size4KiB> for BootinfoFrameAllocator {
 /// once to avoid aliasing `&mut` references (which is undefined
 /// behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -161,3 +166,43 @@ fn translate_address_inner(
     // calculate the physical address by adding the page offset
     Some(frame.start_address() + u64::from(address.page_offset()))
 }
+
+/// Returns whether the given virtual address is mapped in the active page table,
+/// or `None` if it can't be determined because `init` was not called yet.
+///
+/// Unlike `translate_address`, this never panics, even on huge pages, so it can be
+/// used from panic and exception handlers.
+pub fn is_mapped(address: VirtAddr) -> Option<bool> {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags;
+
+    let physical_memory_offset = match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => return None,
+        offset => VirtAddr::new(offset),
+    };
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [
+        address.p4_index(),
+        address.p3_index(),
+        address.p2_index(),
+        address.p1_index(),
+    ];
+    let mut frame = level_4_table_frame;
+
+    for &index in &table_indexes {
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table = unsafe { &*virt.as_ptr::<PageTable>() };
+
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Some(false);
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Some(true);
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+
+    Some(true)
+}
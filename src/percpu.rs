@@ -0,0 +1,199 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+/// Sentinel stored in `current_task` when the CPU is not polling any task
+const NO_TASK: u64 = u64::MAX;
+
+/// Sentinel stored in `coop_budget` when the running code is not constrained
+const NO_BUDGET: u32 = u32::MAX;
+
+/// Per-CPU block of the bootstrap processor
+static BOOT_CPU: PerCpu = PerCpu::new(0);
+
+/// Set once the bootstrap processor's block is installed, so `current` can't read a null GS base
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Data private to a single CPU, reachable through the `GS` base.
+///
+/// Every field is atomic, so the block can be read and updated from interrupt handlers without
+/// taking a lock.
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this block. Must stay the first field: `current` loads it from `gs:[0]`.
+    self_ptr: AtomicU64,
+    cpu_id: AtomicU32,
+    current_task: AtomicU64,
+    coop_budget: AtomicU32,
+    local_apic: AtomicU64,
+    interrupts: AtomicU64,
+    timer_ticks: AtomicU64,
+    idle_ns: AtomicU64,
+    halts: AtomicU64,
+}
+
+impl PerCpu {
+    /// Create an empty per-CPU block for the given CPU id.
+    pub const fn new(cpu_id: u32) -> Self {
+        PerCpu {
+            self_ptr: AtomicU64::new(0),
+            cpu_id: AtomicU32::new(cpu_id),
+            current_task: AtomicU64::new(NO_TASK),
+            coop_budget: AtomicU32::new(NO_BUDGET),
+            local_apic: AtomicU64::new(0),
+            interrupts: AtomicU64::new(0),
+            timer_ticks: AtomicU64::new(0),
+            idle_ns: AtomicU64::new(0),
+            halts: AtomicU64::new(0),
+        }
+    }
+
+    /// Id of the CPU owning this block
+    pub fn cpu_id(&self) -> u32 {
+        self.cpu_id.load(Ordering::Relaxed)
+    }
+
+    /// Id of the task currently being polled on this CPU, if any
+    pub fn current_task(&self) -> Option<u64> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            id => Some(id),
+        }
+    }
+
+    /// Record which task this CPU is polling
+    pub(crate) fn set_current_task(&self, task_id: Option<u64>) {
+        self.current_task
+            .store(task_id.unwrap_or(NO_TASK), Ordering::Relaxed);
+    }
+
+    /// Ready operations left to the task being polled, see `task::coop`
+    pub(crate) fn coop_budget(&self) -> Option<u32> {
+        match self.coop_budget.load(Ordering::Relaxed) {
+            NO_BUDGET => None,
+            budget => Some(budget),
+        }
+    }
+
+    /// Limit the ready operations of the task being polled, or lift the limit
+    pub(crate) fn set_coop_budget(&self, budget: Option<u32>) {
+        self.coop_budget
+            .store(budget.unwrap_or(NO_BUDGET), Ordering::Relaxed);
+    }
+
+    /// Virtual address of this CPU's local APIC registers, if one was registered
+    pub fn local_apic(&self) -> Option<VirtAddr> {
+        match self.local_apic.load(Ordering::Relaxed) {
+            0 => None,
+            addr => Some(VirtAddr::new(addr)),
+        }
+    }
+
+    /// Register the virtual address at which this CPU's local APIC is mapped
+    pub fn set_local_apic(&self, address: VirtAddr) {
+        self.local_apic.store(address.as_u64(), Ordering::Relaxed);
+    }
+
+    /// Number of hardware interrupts handled by this CPU
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+
+    /// Number of timer interrupts handled by this CPU
+    pub fn timer_ticks(&self) -> u64 {
+        self.timer_ticks.load(Ordering::Relaxed)
+    }
+
+    /// Time this CPU spent halted, waiting for work
+    pub fn idle_time(&self) -> Duration {
+        Duration::from_nanos(self.idle_ns.load(Ordering::Relaxed))
+    }
+
+    /// Time this CPU spent running since boot, i.e. not halted
+    pub fn busy_time(&self) -> Duration {
+        crate::time::uptime().saturating_sub(self.idle_time())
+    }
+
+    /// Number of times this CPU halted while idle
+    pub fn halts(&self) -> u64 {
+        self.halts.load(Ordering::Relaxed)
+    }
+
+    /// Record a halt of the given length
+    pub(crate) fn record_idle(&self, duration: Duration) {
+        self.idle_ns
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        self.halts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called by interrupt handlers. Must not block or allocate.
+    pub(crate) fn record_interrupt(&self) {
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called by the timer interrupt handler. Must not block or allocate.
+    pub(crate) fn record_timer_tick(&self) {
+        self.timer_ticks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Install the bootstrap processor's per-CPU block.
+///
+/// Must run before interrupts are enabled, since handlers use `current`.
+pub fn init() {
+    unsafe { install(&BOOT_CPU) };
+    INSTALLED.store(true, Ordering::Release);
+}
+
+/// Point the `GS` base of the calling CPU at the given per-CPU block.
+///
+/// The kernel never runs user code, so `KernelGsBase` is set to the same block: a `swapgs` on
+/// kernel entry will then still leave `GS` pointing at valid per-CPU data.
+///
+/// # Safety
+///
+/// The caller must guarantee that `data` is not installed on any other CPU.
+pub unsafe fn install(data: &'static PerCpu) {
+    let address = VirtAddr::from_ptr(data);
+    data.self_ptr.store(address.as_u64(), Ordering::Relaxed);
+    GsBase::write(address);
+    KernelGsBase::write(address);
+}
+
+/// Per-CPU block of the calling CPU.
+///
+/// # Panics
+/// Panics if called before `init`.
+pub fn current() -> &'static PerCpu {
+    assert!(
+        INSTALLED.load(Ordering::Acquire),
+        "per-CPU data used before percpu::init"
+    );
+
+    let ptr: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, preserves_flags, readonly));
+        &*(ptr as *const PerCpu)
+    }
+}
+
+/// The bootstrap processor's block is reachable through `GS`
+#[test_case]
+fn test_current_is_boot_cpu() {
+    let data = current();
+    assert_eq!(data.cpu_id(), 0);
+    assert_eq!(VirtAddr::from_ptr(data), GsBase::read());
+    assert!(core::ptr::eq(data, &BOOT_CPU));
+}
+
+/// The current task can be set and cleared
+#[test_case]
+fn test_current_task_roundtrip() {
+    let data = current();
+    data.set_current_task(Some(7));
+    assert_eq!(data.current_task(), Some(7));
+    data.set_current_task(None);
+    assert_eq!(data.current_task(), None);
+}
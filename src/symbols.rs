@@ -0,0 +1,221 @@
+use bootloader::bootinfo::MemoryRegionType;
+use bootloader::BootInfo;
+use conquer_once::spin::OnceCell;
+use core::convert::TryInto;
+use core::fmt;
+use rustc_demangle::demangle;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+/// Symbol table of the kernel image, found by `init`
+static KERNEL_TABLE: OnceCell<SymbolTable<'static>> = OnceCell::uninit();
+
+/// A function containing a given address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    /// Mangled function name, as in the symbol table
+    pub name: &'static str,
+    /// Offset of the address from the start of the function
+    pub offset: u64,
+}
+
+impl Symbol {
+    /// Whether the demangled name, without hash, is `path`
+    pub fn is(&self, path: &str) -> bool {
+        use core::fmt::Write;
+
+        let mut matcher = PrefixMatcher {
+            rest: path,
+            matches: true,
+        };
+        let _ = write!(matcher, "{:#}", demangle(self.name));
+        matcher.matches && matcher.rest.is_empty()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}+{:#x}", demangle(self.name), self.offset)
+    }
+}
+
+/// Checks that everything written is a prefix of `rest`, without allocating
+struct PrefixMatcher<'a> {
+    rest: &'a str,
+    matches: bool,
+}
+
+impl fmt::Write for PrefixMatcher<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.rest.strip_prefix(s) {
+            Some(rest) if self.matches => self.rest = rest,
+            _ => self.matches = false,
+        }
+        Ok(())
+    }
+}
+
+/// The `.symtab` section of an ELF file, with its string table
+struct SymbolTable<'a> {
+    symbols: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Find the symbol table of a 64 bit ELF file, returning `None` if it was stripped.
+    fn from_elf(elf: &'a [u8]) -> Option<Self> {
+        if elf.get(..4)? != ELF_MAGIC || *elf.get(4)? != ELF_CLASS_64 {
+            return None;
+        }
+        let section_headers = read_u64(elf, 0x28)? as usize;
+        let count = usize::from(read_u16(elf, 0x3c)?);
+        let section = |index: usize| {
+            let start = section_headers.checked_add(index.checked_mul(SECTION_HEADER_SIZE)?)?;
+            elf.get(start..start.checked_add(SECTION_HEADER_SIZE)?)
+        };
+        let contents = |header: &[u8]| {
+            let start = read_u64(header, 24)? as usize;
+            let size = read_u64(header, 32)? as usize;
+            elf.get(start..start.checked_add(size)?)
+        };
+
+        let symtab = (0..count)
+            .filter_map(section)
+            .find(|header| read_u32(header, 4) == Some(SHT_SYMTAB))?;
+        let strtab = section(read_u32(symtab, 40)? as usize)?;
+        Some(SymbolTable {
+            symbols: contents(symtab)?,
+            strings: contents(strtab)?,
+        })
+    }
+
+    /// Name offset, type, start and size of every symbol
+    fn symbols(&self) -> impl Iterator<Item = (usize, u8, u64, u64)> + 'a {
+        self.symbols.chunks_exact(SYMBOL_SIZE).map(|symbol| {
+            (
+                read_u32(symbol, 0).unwrap_or(0) as usize,
+                symbol[4] & 0xf,
+                read_u64(symbol, 8).unwrap_or(0),
+                read_u64(symbol, 16).unwrap_or(0),
+            )
+        })
+    }
+
+    fn name(&self, offset: usize) -> Option<&'a str> {
+        let bytes = self.strings.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    /// Find the function containing `address`. The table is not sorted, but this only runs to
+    /// print backtraces.
+    fn resolve(&self, address: u64) -> Option<(&'a str, u64)> {
+        let (name, _, start, _) = self.symbols().find(|&(_, kind, start, size)| {
+            kind == STT_FUNC && start <= address && address - start < size
+        })?;
+        Some((self.name(name)?, address - start))
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// Find the symbol table in the kernel ELF file, which the bootloader leaves in memory.
+///
+/// A kernel without symbol table still works, but backtraces only show raw addresses, so this
+/// complains loudly about it. Needs the physical memory mapping of the bootloader.
+pub fn init(boot_info: &'static BootInfo) {
+    let table = boot_info
+        .memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Kernel)
+        .find_map(|region| {
+            let start = boot_info.physical_memory_offset + region.range.start_addr();
+            let len = (region.range.end_addr() - region.range.start_addr()) as usize;
+            let bytes = unsafe { core::slice::from_raw_parts(start as *const u8, len) };
+            SymbolTable::from_elf(bytes)
+        });
+    match table {
+        Some(table) => {
+            KERNEL_TABLE.try_init_once(|| table).ok();
+        }
+        None => {
+            crate::println!("WARNING: kernel image has no symbol table, backtraces are raw");
+            crate::serial_println!("WARNING: kernel image has no symbol table, backtraces are raw");
+        }
+    }
+}
+
+/// Find the function containing the given address.
+///
+/// Returns `None` if the address is outside every known function, or before `init` found the
+/// symbol table of the kernel.
+pub fn resolve(address: u64) -> Option<Symbol> {
+    let (name, offset) = KERNEL_TABLE.try_get().ok()?.resolve(address)?;
+    Some(Symbol { name, offset })
+}
+
+/// Whether `init` found the symbol table of the kernel
+pub fn available() -> bool {
+    KERNEL_TABLE.is_initialized()
+}
+
+/// Handcrafted tables are searched correctly
+#[test_case]
+fn test_resolve_table() {
+    let mut symbols = [0u8; 3 * SYMBOL_SIZE];
+    // symbol 0: data at 0x1000, symbol 1: "a" at 0x1000 size 0x10, symbol 2: "b" at 0x2000
+    let entries: [(u32, u8, u64, u64); 3] = [
+        (1, 1, 0x1000, 0x8),
+        (1, 2, 0x1000, 0x10),
+        (3, 2, 0x2000, 0x20),
+    ];
+    for (symbol, &(name, kind, start, size)) in symbols.chunks_exact_mut(SYMBOL_SIZE).zip(&entries)
+    {
+        symbol[0..4].copy_from_slice(&name.to_le_bytes());
+        symbol[4] = kind;
+        symbol[8..16].copy_from_slice(&start.to_le_bytes());
+        symbol[16..24].copy_from_slice(&size.to_le_bytes());
+    }
+    let table = SymbolTable {
+        symbols: &symbols,
+        strings: b"\0a\0b\0",
+    };
+    assert_eq!(table.resolve(0xfff), None);
+    assert_eq!(table.resolve(0x1000), Some(("a", 0)));
+    assert_eq!(table.resolve(0x100f), Some(("a", 0xf)));
+    assert_eq!(table.resolve(0x1010), None);
+    assert_eq!(table.resolve(0x2004), Some(("b", 4)));
+    assert_eq!(table.resolve(0x2020), None);
+
+    assert!(SymbolTable::from_elf(&[0; 64]).is_none());
+}
+
+/// The kernel's own functions are found and demangled
+#[test_case]
+fn test_resolve_kernel_function() {
+    assert!(available(), "no symbol table in the kernel image");
+    let address = resolve_target as *const () as u64;
+    let symbol = resolve(address + 1).expect("function not in symbol table");
+    assert!(symbol.is("rust_os::symbols::resolve_target"));
+    assert!(!symbol.is("rust_os::symbols::resolve"));
+    assert_eq!(symbol.offset, 1);
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn resolve_target() {}
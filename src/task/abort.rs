@@ -0,0 +1,58 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Waker;
+use futures_util::task::AtomicWaker;
+
+/// Abort request of a task, shared by the task and its handles
+pub(super) struct AbortState {
+    aborted: AtomicBool,
+    /// Waker of the task, registered by the executor when it spawns the task
+    waker: AtomicWaker,
+}
+
+impl AbortState {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(AbortState {
+            aborted: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        })
+    }
+
+    pub(super) fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    /// Set the waker used to reschedule the task when it is aborted.
+    pub(super) fn register(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+}
+
+/// Handle to stop a spawned task.
+///
+/// Aborting a task wakes it: instead of polling it, the executor then drops it, running the
+/// destructors of its future. Its `JoinHandle` resolves to `JoinError::Cancelled`, unless the task
+/// already completed.
+#[derive(Clone)]
+pub struct AbortHandle {
+    state: Arc<AbortState>,
+}
+
+impl AbortHandle {
+    pub(super) fn new(state: Arc<AbortState>) -> Self {
+        AbortHandle { state }
+    }
+
+    /// Ask the executor to drop the task.
+    ///
+    /// This doesn't allocate or block, so it can be called from interrupt handlers.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.waker.wake();
+    }
+
+    /// Whether `abort` was called
+    pub fn is_aborted(&self) -> bool {
+        self.state.is_aborted()
+    }
+}
@@ -0,0 +1,234 @@
+use super::timer::{self, Sleep};
+use crate::time::Instant;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+pub use futures_util::future::Either;
+
+/// Error returned by a `Timeout` whose deadline passed before the future completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Future returned by `timeout` and `timeout_at`
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Run `future` for at most `duration`.
+///
+/// The deadline is a timer of `task::timer`, so the task is woken by the timer interrupt even if
+/// `future` never wakes it.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: timer::sleep(duration),
+    }
+}
+
+/// Run `future` until `deadline` at most.
+pub fn timeout_at<F: Future>(deadline: Instant, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: timer::sleep_until(deadline),
+    }
+}
+
+impl<F> Timeout<F> {
+    /// Instant at which the future is given up
+    pub fn deadline(&self) -> Instant {
+        self.sleep.deadline()
+    }
+
+    /// Consume the timeout, returning the future
+    pub fn into_inner(self) -> F {
+        self.future
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // safety: `future` is pinned along with self and never moved, `sleep` is Unpin
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        // a future completing right at the deadline still counts as completed
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Future returned by `race`
+pub struct Race<A, B> {
+    left: A,
+    right: B,
+}
+
+/// Wait for the first of two futures to complete, dropping the other one.
+///
+/// When both are ready, `left` wins: it is always polled first.
+pub fn race<A: Future, B: Future>(left: A, right: B) -> Race<A, B> {
+    Race { left, right }
+}
+
+impl<A: Future, B: Future> Future for Race<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // safety: the futures are pinned along with self and never moved
+        let this = unsafe { self.get_unchecked_mut() };
+        let left = unsafe { Pin::new_unchecked(&mut this.left) };
+        if let Poll::Ready(output) = left.poll(cx) {
+            return Poll::Ready(Either::Left(output));
+        }
+        let right = unsafe { Pin::new_unchecked(&mut this.right) };
+        right.poll(cx).map(Either::Right)
+    }
+}
+
+/// Future returned by `join_all`
+pub struct JoinAll<F: Future> {
+    /// Futures still running, `None` once completed
+    futures: Vec<Option<Pin<Box<F>>>>,
+    outputs: Vec<Option<F::Output>>,
+}
+
+/// Wait for every future to complete, returning their outputs in order.
+pub fn join_all<I>(futures: I) -> JoinAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    let futures: Vec<_> = futures.into_iter().map(|f| Some(Box::pin(f))).collect();
+    let outputs = futures.iter().map(|_| None).collect();
+    JoinAll { futures, outputs }
+}
+
+// the futures are boxed and the outputs are never pinned
+impl<F: Future> Unpin for JoinAll<F> {}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Vec<F::Output>> {
+        let this = &mut *self;
+        let mut done = true;
+        for (slot, output) in this.futures.iter_mut().zip(&mut this.outputs) {
+            if let Some(future) = slot {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(value) => {
+                        *output = Some(value);
+                        *slot = None;
+                    }
+                    Poll::Pending => done = false,
+                }
+            }
+        }
+        if !done {
+            return Poll::Pending;
+        }
+        let outputs = core::mem::take(&mut this.outputs);
+        Poll::Ready(
+            outputs
+                .into_iter()
+                .map(|output| output.expect("JoinAll polled after completion"))
+                .collect(),
+        )
+    }
+}
+
+/// Wait for the first of several futures to complete, and run the branch of that future.
+///
+/// Each branch reads `pattern = future => expression`. The other futures are dropped. Futures
+/// are polled in the order of the branches, so earlier ones win when several are ready. Patterns
+/// must be irrefutable. Like `.await`, this can only be used in async code.
+///
+/// ```ignore
+/// select! {
+///     scancode = scancodes.next() => handle(scancode),
+///     () = timer::sleep(Duration::from_secs(1)) => println!("no key pressed"),
+/// }
+/// ```
+#[macro_export]
+macro_rules! select {
+    (@race $future:expr) => {
+        $future
+    };
+    (@race $future:expr, $($rest:expr),+) => {
+        $crate::task::combinators::race($future, $crate::select!(@race $($rest),+))
+    };
+    (@match $value:expr; $pattern:pat => $body:expr) => {
+        match $value {
+            $pattern => $body,
+        }
+    };
+    (@match $value:expr; $pattern:pat => $body:expr, $($rest_pattern:pat => $rest_body:expr),+) => {
+        match $value {
+            $crate::task::combinators::Either::Left($pattern) => $body,
+            $crate::task::combinators::Either::Right(rest) => {
+                $crate::select!(@match rest; $($rest_pattern => $rest_body),+)
+            }
+        }
+    };
+    ($($pattern:pat = $future:expr => $body:expr),+ $(,)?) => {
+        $crate::select!(
+            @match $crate::select!(@race $($future),+).await;
+            $($pattern => $body),+
+        )
+    };
+}
+
+/// Timeouts give up on futures that don't complete in time
+#[test_case]
+fn test_timeout() {
+    use futures_util::future::{pending, ready};
+
+    let start = Instant::now();
+    let result = timer::block_on(Box::pin(timeout(Duration::from_millis(2), pending::<()>())));
+    assert_eq!(result, Err(Elapsed));
+    assert!(start.elapsed() >= Duration::from_millis(2));
+
+    let result = timer::block_on(Box::pin(timeout(Duration::from_secs(10), ready(7))));
+    assert_eq!(result, Ok(7));
+    assert_eq!(timer::pending_timers(), 0);
+}
+
+/// The first future to complete wins, and `select!` runs its branch
+#[test_case]
+fn test_select() {
+    use futures_util::future::pending;
+
+    let winner = timer::block_on(Box::pin(async {
+        crate::select! {
+            () = pending::<()>() => 0,
+            () = timer::sleep(Duration::from_millis(2)) => 1,
+            () = timer::sleep(Duration::from_secs(10)) => 2,
+        }
+    }));
+    assert_eq!(winner, 1);
+    assert_eq!(timer::pending_timers(), 0);
+
+    let first = race(async { 'a' }, async { 1 });
+    assert!(matches!(
+        timer::block_on(Box::pin(first)),
+        Either::Left('a')
+    ));
+}
+
+/// Joining waits for every future and keeps the order of their outputs
+#[test_case]
+fn test_join_all() {
+    let futures = (0..3u64).map(|n| async move {
+        timer::sleep(Duration::from_millis(3 - n)).await;
+        n
+    });
+    assert_eq!(timer::block_on(join_all(futures)), [0, 1, 2]);
+    let empty: Vec<futures_util::future::Ready<()>> = Vec::new();
+    assert!(timer::block_on(join_all(empty)).is_empty());
+}
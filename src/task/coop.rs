@@ -0,0 +1,118 @@
+//! Cooperative scheduling budget.
+//!
+//! A task whose futures are always ready, like a stream under an input flood, would never return
+//! to the executor. The executor therefore gives each poll a budget of `BUDGET` ready operations.
+//! Leaf futures, like the receivers of channels, spend it through `poll_budgeted`: once it is
+//! exhausted they return `Pending` and wake the task right away, which moves it to the back of its
+//! queue so that the other ready tasks get to run.
+//!
+//! Code running outside of an executor poll has no budget and is never made to yield.
+
+use crate::percpu;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Ready operations a task may perform in one poll
+pub const BUDGET: u32 = 128;
+
+/// Give the task about to be polled a fresh budget.
+pub(super) fn start_poll() {
+    percpu::current().set_coop_budget(Some(BUDGET));
+}
+
+/// Lift the budget once the poll returned.
+pub(super) fn end_poll() {
+    percpu::current().set_coop_budget(None);
+}
+
+/// Run the poll of a leaf future if the task has budget left, spending one unit if it is ready.
+///
+/// Without budget left, the task is woken and `Pending` returned without calling `poll`.
+pub fn poll_budgeted<T>(cx: &mut Context, poll: impl FnOnce(&mut Context) -> Poll<T>) -> Poll<T> {
+    let cpu = percpu::current();
+    let budget = cpu.coop_budget();
+    if budget == Some(0) {
+        cx.waker().wake_by_ref();
+        return Poll::Pending;
+    }
+    let result = poll(cx);
+    if let (Poll::Ready(_), Some(budget)) = (&result, budget) {
+        cpu.set_coop_budget(Some(budget - 1));
+    }
+    result
+}
+
+/// Future returned by `yield_now`
+pub struct YieldNow {
+    yielded: bool,
+}
+
+/// Let the other ready tasks run before continuing.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Yielding lets the other ready tasks run first
+#[test_case]
+fn test_yield_now() {
+    use super::executor::Executor;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    let output = order.clone();
+    executor.spawn(async move {
+        output.lock().push(1);
+        yield_now().await;
+        output.lock().push(3);
+    });
+    let output = order.clone();
+    executor.spawn(async move { output.lock().push(2) });
+    executor.run_ready_tasks();
+    assert_eq!(*order.lock(), [1, 2, 3]);
+}
+
+/// A task whose channel always has data is made to yield once its budget is spent
+#[test_case]
+fn test_budget() {
+    use super::executor::Executor;
+    use super::sync::mpsc;
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    for value in 0..BUDGET * 2 {
+        sender.send(value).unwrap();
+    }
+    drop(sender);
+    let received = Arc::new(Mutex::new(0));
+    let received_before_other = Arc::new(Mutex::new(None));
+    let mut executor = Executor::new();
+    let output = received.clone();
+    executor.spawn(async move {
+        while receiver.recv().await.is_some() {
+            *output.lock() += 1;
+        }
+    });
+    let (input, output) = (received.clone(), received_before_other.clone());
+    executor.spawn(async move { *output.lock() = Some(*input.lock()) });
+    executor.run_ready_tasks();
+    assert_eq!(*received_before_other.lock(), Some(BUDGET));
+    assert_eq!(*received.lock(), BUDGET * 2);
+}
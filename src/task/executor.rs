@@ -1,16 +1,289 @@
-use super::{Task, TaskID};
-use alloc::collections::BTreeMap;
+use super::stats::{ExecutorStats, TaskStats};
+use super::{coop, timer};
+use super::{JoinHandle, Priority, Task, TaskID};
+use crate::percpu;
+use crate::serial_println;
+use crate::thread;
+use crate::time::{self, tsc, Instant};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::rc::Rc;
 use alloc::sync::Arc;
 use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::task::Waker;
 use core::task::{Context, Poll};
-use crossbeam_queue::ArrayQueue;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Number of times a ready queue may be passed over for higher priority ones before it is served
+const AGING_THRESHOLD: u32 = 8;
+
+/// Polls taking longer than this are reported, unless changed by `Executor::set_poll_budget`
+pub const DEFAULT_POLL_BUDGET: Duration = Duration::from_millis(10);
 
 /// Simple single-threaded executor with waker support
+///
+/// Ready tasks of higher priority are polled first. To keep lower priorities from starving, a
+/// queue passed over `AGING_THRESHOLD` times in a row is served next.
+///
+/// Polls, wakeups and the TSC cycles spent polling are counted for every task, see `task_list`
+/// and `stats`. Polls taking longer than the poll budget are counted as long polls, and logged to
+/// serial on the first one of a task, then each time its count doubles.
 pub struct Executor {
     tasks: BTreeMap<TaskID, Task>,
-    task_queue: Arc<ArrayQueue<TaskID>>,
-    waker_cache: BTreeMap<TaskID, Waker>,
+    task_queues: ReadyQueues,
+    waker_cache: BTreeMap<TaskID, Arc<TaskWaker>>,
+    shared: Rc<Shared>,
+    poll_budget: Option<Duration>,
+}
+
+/// State shared by an executor and its spawners
+struct Shared {
+    /// Tasks queued by `Spawner`s, added to `tasks` before the next poll
+    spawned: RefCell<VecDeque<Task>>,
+    /// Description of every task in `tasks`, for listings
+    records: RefCell<BTreeMap<TaskID, TaskRecord>>,
+    /// Task counts, and the activity of the tasks no longer in `records`
+    totals: RefCell<ExecutorStats>,
+}
+
+struct TaskRecord {
+    name: Option<&'static str>,
+    priority: Priority,
+    spawned_at: Instant,
+    waker: Arc<TaskWaker>,
+    /// Counters updated by the executor, the wakeups are counted by the waker
+    stats: TaskStats,
+}
+
+impl TaskRecord {
+    fn stats(&self) -> TaskStats {
+        TaskStats {
+            wakeups: self.waker.wakeups.load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+}
+
+impl Shared {
+    /// Forget a task that completed or was aborted, keeping its activity in the totals.
+    fn remove(&self, task_id: TaskID, aborted: bool) {
+        let record = self.records.borrow_mut().remove(&task_id);
+        let mut totals = self.totals.borrow_mut();
+        if let Some(record) = record {
+            totals.add(&record.stats());
+        }
+        if aborted {
+            totals.aborted += 1;
+        } else {
+            totals.completed += 1;
+        }
+    }
+
+    fn stats(&self) -> ExecutorStats {
+        let mut stats = *self.totals.borrow();
+        for record in self.records.borrow().values() {
+            stats.add(&record.stats());
+        }
+        stats
+    }
+
+    fn task_list(&self) -> Vec<TaskInfo> {
+        let running = percpu::current().current_task();
+        self.records
+            .borrow()
+            .iter()
+            .map(|(task_id, record)| TaskInfo {
+                id: task_id.0,
+                name: record.name,
+                priority: record.priority,
+                spawned_at: record.spawned_at,
+                stats: record.stats(),
+                state: if running == Some(task_id.0) {
+                    TaskState::Running
+                } else if record.waker.scheduled.load(Ordering::Acquire) {
+                    TaskState::Ready
+                } else {
+                    TaskState::Pending
+                },
+            })
+            .collect()
+    }
+}
+
+/// What a live task is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Woken, waiting in its queue to be polled
+    Ready,
+    /// Waiting to be woken
+    Pending,
+    /// Being polled
+    Running,
+}
+
+/// Description of a live task, see `Executor::task_list`
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    /// Unique id of the task, as reported by `PerCpu::current_task`
+    pub id: u64,
+    /// Name given with `Task::named` or `Task::with_name`
+    pub name: Option<&'static str>,
+    /// Priority of the task
+    pub priority: Priority,
+    /// When the task was handed to the executor
+    pub spawned_at: Instant,
+    /// What the task is doing
+    pub state: TaskState,
+    /// Activity of the task so far
+    pub stats: TaskStats,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "task {} {} ({:?}, {:?}, spawned {:?} ago): {} polls ({} long), {} wakeups, ",
+            self.id,
+            self.name.unwrap_or("<unnamed>"),
+            self.priority,
+            self.state,
+            self.spawned_at.elapsed(),
+            self.stats.polls,
+            self.stats.long_polls,
+            self.stats.wakeups,
+        )?;
+        match self.stats.cpu_time() {
+            Some(cpu_time) => write!(f, "{:?} CPU", cpu_time),
+            None => write!(f, "{} cycles", self.stats.cpu_cycles),
+        }
+    }
+}
+
+/// Cloneable handle spawning tasks onto an `Executor`, for use by its running tasks.
+///
+/// The tasks are only queued, and picked up by the executor before it polls its next task. A
+/// spawner is bound to the thread of its executor, and must not be used from interrupt handlers
+/// since it allocates.
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Rc<Shared>,
+}
+
+impl Spawner {
+    /// Spawn a task running `future` with normal priority, returning a handle to its output.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::joinable(future, Priority::Normal);
+        self.spawn_task(task);
+        handle
+    }
+
+    /// Add the given task to the executor
+    pub fn spawn_task(&self, task: Task) {
+        self.shared.spawned.borrow_mut().push_back(task);
+    }
+
+    /// Every task of the executor, see `Executor::task_list`
+    pub fn task_list(&self) -> Vec<TaskInfo> {
+        self.shared.task_list()
+    }
+
+    /// Activity of the executor, see `Executor::stats`
+    pub fn stats(&self) -> ExecutorStats {
+        self.shared.stats()
+    }
+}
+
+/// Queue of tasks ready to be polled, to which wakers push from any context.
+///
+/// A task is queued at most once, see `TaskWaker::scheduled`, so reserving room for every task
+/// when it is spawned guarantees that pushing from an interrupt handler never allocates.
+pub(super) struct RunQueue {
+    /// Locked with interrupts disabled
+    queue: Mutex<VecDeque<TaskID>>,
+}
+
+impl RunQueue {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(RunQueue {
+            queue: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    pub(super) fn push(&self, task_id: TaskID) {
+        without_interrupts(|| self.queue.lock().push_back(task_id));
+    }
+
+    pub(super) fn pop(&self) -> Option<TaskID> {
+        without_interrupts(|| self.queue.lock().pop_front())
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        without_interrupts(|| self.queue.lock().is_empty())
+    }
+
+    /// Make room for `tasks` tasks in total.
+    pub(super) fn reserve(&self, tasks: usize) {
+        without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            let additional = tasks.saturating_sub(queue.len());
+            queue.reserve(additional);
+        });
+    }
+}
+
+/// Ready queues, one per priority
+struct ReadyQueues {
+    queues: [Arc<RunQueue>; Priority::COUNT],
+    /// Number of times each queue was passed over while not empty
+    skipped: [u32; Priority::COUNT],
+}
+
+impl ReadyQueues {
+    fn new() -> Self {
+        ReadyQueues {
+            queues: core::array::from_fn(|_| RunQueue::new()),
+            skipped: [0; Priority::COUNT],
+        }
+    }
+
+    fn queue(&self, priority: Priority) -> &Arc<RunQueue> {
+        &self.queues[priority.index()]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    /// Take the next task to poll: from the highest priority queue that isn't empty, unless a
+    /// lower one has aged enough.
+    fn pop(&mut self) -> Option<TaskID> {
+        let Self { queues, skipped } = self;
+        let aged = (0..Priority::COUNT)
+            .find(|&level| skipped[level] >= AGING_THRESHOLD && !queues[level].is_empty());
+        let level = aged.or_else(|| {
+            (0..Priority::COUNT)
+                .rev()
+                .find(|&level| !queues[level].is_empty())
+        })?;
+        let task_id = queues[level].pop()?;
+
+        skipped[level] = 0;
+        for (queue, skipped) in queues[..level].iter().zip(&mut skipped[..level]) {
+            if !queue.is_empty() {
+                *skipped += 1;
+            }
+        }
+        Some(task_id)
+    }
 }
 
 impl Executor {
@@ -19,21 +292,82 @@ impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queues: ReadyQueues::new(),
             waker_cache: BTreeMap::new(),
+            shared: Rc::new(Shared {
+                spawned: RefCell::new(VecDeque::new()),
+                records: RefCell::new(BTreeMap::new()),
+                totals: RefCell::new(ExecutorStats::default()),
+            }),
+            poll_budget: Some(DEFAULT_POLL_BUDGET),
         }
     }
 
+    /// Handle to spawn tasks onto this executor once it runs
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Every task of this executor, ordered by id.
+    ///
+    /// Tasks queued by a `Spawner` are only listed once the executor picked them up.
+    pub fn task_list(&self) -> Vec<TaskInfo> {
+        self.shared.task_list()
+    }
+
+    /// Activity of this executor since its creation, summed over every task it ran
+    pub fn stats(&self) -> ExecutorStats {
+        self.shared.stats()
+    }
+
+    /// Report polls taking longer than `budget`, or none at all.
+    ///
+    /// Polls are timed with `time::Instant`, so without the TSC those shorter than the resolution
+    /// of the clock may go unnoticed, see `time::resolution`.
+    pub fn set_poll_budget(&mut self, budget: Option<Duration>) {
+        self.poll_budget = budget;
+    }
+
+    /// Spawn a task running `future` with normal priority, returning a handle to its output.
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::joinable(future, Priority::Normal);
+        self.spawn_task(task);
+        handle
+    }
+
     /// Add the given task to the task list
-    pub fn spawn(&mut self, task: Task) {
+    pub fn spawn_task(&mut self, task: Task) {
         let task_id = task.id;
+        let queue = self.task_queues.queue(task.priority).clone();
+        // every task may be queued at once
+        queue.reserve(self.tasks.len() + 1);
+        let waker = TaskWaker::new(task_id, queue);
+        task.abort.register(&Waker::from(waker.clone()));
+        let record = TaskRecord {
+            name: task.name,
+            priority: task.priority,
+            spawned_at: Instant::now(),
+            waker: waker.clone(),
+            stats: TaskStats::default(),
+        };
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        self.shared.records.borrow_mut().insert(task_id, record);
+        self.shared.totals.borrow_mut().spawned += 1;
+        waker.wake_task();
+        self.waker_cache.insert(task_id, waker);
     }
 
     /// Run all tasks, never exits. Puts the processor to sleep if no tasks are active.
+    ///
+    /// This can run in its own kernel thread, see `thread::spawn_thread`: it then yields to the
+    /// other threads instead of sleeping.
     pub fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
@@ -42,39 +376,113 @@ impl Executor {
     }
 
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts;
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
+        if !self.task_queues.is_empty() {
+            interrupts::enable();
+        } else if thread::ready_count() > 0 {
+            // running as a thread, let the others use the CPU
             interrupts::enable();
+            thread::yield_now();
+        } else {
+            let next_deadline = timer::next_deadline()
+                .into_iter()
+                .chain(thread::next_wakeup())
+                .min();
+            time::idle(next_deadline);
         }
     }
 
-    /// Run all pending tasks, creating a waker to re-add it to the queue when it is ready again
-    fn run_ready_tasks(&mut self) {
-        // destructure self to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+    /// Move the tasks queued by spawners to the task list.
+    fn add_spawned_tasks(&mut self) {
+        loop {
+            // don't keep the queue borrowed while spawning
+            let task = self.shared.spawned.borrow_mut().pop_front();
+            match task {
+                Some(task) => self.spawn_task(task),
+                None => break,
+            }
+        }
+    }
 
-        while let Ok(task_id) = task_queue.pop() {
+    /// Run all pending tasks, creating a waker to re-add it to its queue when it is ready again
+    pub(super) fn run_ready_tasks(&mut self) {
+        loop {
+            self.add_spawned_tasks();
+            let task_id = match self.task_queues.pop() {
+                Some(task_id) => task_id,
+                None => break,
+            };
+
+            // destructure self to avoid borrow checker errors
+            let Self {
+                tasks,
+                waker_cache,
+                shared,
+                poll_budget,
+                ..
+            } = self;
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue, // task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            let task_waker = &waker_cache[&task_id];
+            if task.is_aborted() {
+                // dropping the task runs the destructors of its future
+                task_waker.retire();
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+                shared.remove(task_id, true);
+                continue;
+            }
+            // wakeups from now on must poll the task again
+            task_waker.scheduled.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            let cpu = percpu::current();
+            cpu.set_current_task(Some(task_id.0));
+            let start = tsc::read();
+            let started = Instant::now();
+            coop::start_poll();
+            let poll = task.poll(&mut context);
+            coop::end_poll();
+            let cycles = tsc::read().wrapping_sub(start);
+            let duration = started.elapsed();
+            cpu.set_current_task(None);
+            let mut long_poll = None;
+            if let Some(record) = shared.records.borrow_mut().get_mut(&task_id) {
+                record.stats.polls += 1;
+                record.stats.cpu_cycles += cycles;
+                if let Some(budget) = *poll_budget {
+                    if duration > budget {
+                        record.stats.long_polls += 1;
+                        long_poll = Some((record.name, record.stats.long_polls, budget));
+                    }
+                }
+            }
+            // the count is in the task list, so a task polling long every time only gets logged
+            // now and then
+            if let Some((name, count, budget)) = long_poll {
+                if count.is_power_of_two() {
+                    serial_println!(
+                        "WARNING: long poll of task {} {}: {:?}, budget {:?} ({} so far)",
+                        task_id.0,
+                        name.unwrap_or("<unnamed>"),
+                        duration,
+                        budget,
+                        count
+                    );
+                }
+            }
+            match poll {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    if let Some(task_waker) = waker_cache.remove(&task_id) {
+                        task_waker.retire();
+                    }
+                    shared.remove(task_id, false);
                 }
                 Poll::Pending => {}
             }
@@ -82,31 +490,266 @@ impl Executor {
     }
 }
 
-struct TaskWaker {
+pub(super) struct TaskWaker {
     task_id: TaskID,
-    task_queue: Arc<ArrayQueue<TaskID>>,
+    /// Set while the task is in its queue, so that it is queued only once however many times it
+    /// is woken. Cleared right before polling the task, and set for good once it is gone.
+    pub(super) scheduled: AtomicBool,
+    /// Number of calls to `wake`, counted even when the task was already queued
+    wakeups: AtomicU64,
+    task_queue: Arc<RunQueue>,
 }
 
 impl TaskWaker {
-    #[allow(clippy::new_ret_no_self)]
-    fn new(task_id: TaskID, task_queue: Arc<ArrayQueue<TaskID>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    pub(super) fn new(task_id: TaskID, task_queue: Arc<RunQueue>) -> Arc<Self> {
+        Arc::new(TaskWaker {
             task_id,
+            scheduled: AtomicBool::new(false),
+            wakeups: AtomicU64::new(0),
             task_queue,
-        }))
+        })
     }
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+    pub(super) fn wake_task(&self) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            self.task_queue.push(self.task_id);
+        }
+    }
+
+    /// Ignore wakeups once the task is gone.
+    pub(super) fn retire(&self) {
+        self.scheduled.store(true, Ordering::Release);
     }
 }
 
 impl Wake for TaskWaker {
     fn wake(self: Arc<Self>) {
-        self.wake_task();
+        self.wake_by_ref();
     }
 
     fn wake_by_ref(self: &Arc<Self>) {
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
         self.wake_task();
     }
 }
+
+/// Ready tasks are polled highest priority first
+#[test_case]
+fn test_priority_order() {
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &priority in &[Priority::Low, Priority::High, Priority::Normal] {
+        let order = order.clone();
+        executor.spawn_task(Task::with_priority(
+            async move { order.lock().push(priority) },
+            priority,
+        ));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(
+        *order.lock(),
+        [Priority::High, Priority::Normal, Priority::Low]
+    );
+}
+
+/// Low priority tasks still run while higher priority ones keep the queues busy
+#[test_case]
+fn test_aging() {
+    let mut queues = ReadyQueues::new();
+    let high = TaskID::new();
+    let low = TaskID::new();
+    queues.queue(Priority::Low).push(low);
+    queues.queue(Priority::High).push(high);
+
+    for _ in 0..AGING_THRESHOLD {
+        assert_eq!(queues.pop(), Some(high));
+        queues.queue(Priority::High).push(high);
+    }
+    assert_eq!(queues.pop(), Some(low));
+    assert_eq!(queues.pop(), Some(high));
+    assert_eq!(queues.pop(), None);
+}
+
+/// Awaiting the handle of a completed task gives its output
+#[test_case]
+fn test_join_handle() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { 6 * 7 });
+    let result = Arc::new(spin::Mutex::new(None));
+    let output = result.clone();
+    executor.spawn(async move { *output.lock() = Some(handle.await) });
+    executor.run_ready_tasks();
+    assert_eq!(*result.lock(), Some(Ok(42)));
+}
+
+/// Dropping an unfinished task cancels it
+#[test_case]
+fn test_cancelled() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(futures_util::future::pending::<()>());
+    executor.run_ready_tasks();
+    assert!(!handle.is_finished());
+    drop(executor);
+    assert!(handle.is_finished());
+    assert!(handle.is_cancelled());
+}
+
+/// Running tasks can spawn new ones
+#[test_case]
+fn test_spawner() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let result = Arc::new(spin::Mutex::new(None));
+    let output = result.clone();
+    executor.spawn(async move {
+        let child = spawner.spawn(async { 6 * 7 });
+        let sibling = spawner.clone().spawn(async { 1 });
+        *output.lock() = Some(child.await.unwrap() + sibling.await.unwrap());
+    });
+    executor.run_ready_tasks();
+    assert_eq!(*result.lock(), Some(43));
+    assert!(executor.tasks.is_empty());
+}
+
+/// Aborting a task drops it along with its waker, and ignores its later wakeups
+#[test_case]
+fn test_abort() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            DROPPED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    let mut executor = Executor::new();
+    let (sender, receiver) = super::sync::oneshot::channel::<()>();
+    let handle = executor.spawn(async move {
+        let _guard = Guard;
+        receiver.await.ok();
+    });
+    executor.run_ready_tasks();
+    assert_eq!(executor.waker_cache.len(), 1);
+
+    handle.abort();
+    executor.run_ready_tasks();
+    assert!(DROPPED.load(Ordering::Relaxed));
+    assert!(handle.is_cancelled());
+    assert!(executor.tasks.is_empty());
+    assert!(executor.waker_cache.is_empty());
+
+    // a stale wakeup, the sender being dropped with the task gone
+    drop(sender);
+    executor.run_ready_tasks();
+    assert!(executor.tasks.is_empty());
+}
+
+/// The queues grow with the number of tasks, and wakeups of a queued task don't queue it again
+#[test_case]
+fn test_run_queue() {
+    use core::sync::atomic::AtomicUsize;
+
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..500 {
+        executor.spawn(async {});
+    }
+    executor.spawn(futures_util::future::poll_fn(|_| {
+        POLLS.fetch_add(1, Ordering::Relaxed);
+        Poll::<()>::Pending
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(executor.tasks.len(), 1);
+    assert_eq!(POLLS.load(Ordering::Relaxed), 1);
+
+    let waker = Waker::from(executor.waker_cache.values().next().unwrap().clone());
+    for _ in 0..10 {
+        waker.wake_by_ref();
+    }
+    executor.run_ready_tasks();
+    assert_eq!(POLLS.load(Ordering::Relaxed), 2);
+}
+
+/// Live tasks are listed with their name and state
+#[test_case]
+fn test_task_list() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let (sender, receiver) = super::sync::oneshot::channel::<()>();
+    executor.spawn_task(Task::named("waiting", async move {
+        receiver.await.ok();
+    }));
+    let states = Arc::new(spin::Mutex::new(Vec::new()));
+    let output = states.clone();
+    executor.spawn(async move {
+        let list = spawner.task_list();
+        *output.lock() = list.iter().map(|info| info.state).collect();
+    });
+
+    let list = executor.task_list();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].name, Some("waiting"));
+    assert_eq!(list[1].name, None);
+    assert!(list.iter().all(|info| info.state == TaskState::Ready));
+
+    executor.run_ready_tasks();
+    assert_eq!(*states.lock(), [TaskState::Pending, TaskState::Running]);
+    let list = executor.task_list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].state, TaskState::Pending);
+    assert!(list[0].spawned_at <= Instant::now());
+
+    drop(sender);
+    executor.run_ready_tasks();
+    assert!(executor.task_list().is_empty());
+}
+
+/// Polls and wakeups are counted per task, and summed once the task is gone
+#[test_case]
+fn test_stats() {
+    let mut executor = Executor::new();
+    let (sender, receiver) = super::sync::oneshot::channel::<()>();
+    executor.spawn_task(Task::named("waiting", async move {
+        receiver.await.ok();
+    }));
+    executor.spawn(async {});
+    executor.run_ready_tasks();
+
+    let list = executor.task_list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].stats.polls, 1);
+    assert_eq!(list[0].stats.wakeups, 0);
+    assert!(list[0].stats.cpu_cycles > 0);
+
+    drop(sender);
+    executor.run_ready_tasks();
+    let stats = executor.stats();
+    assert_eq!((stats.spawned, stats.completed, stats.live()), (2, 2, 0));
+    assert_eq!((stats.polls, stats.wakeups), (3, 1));
+
+    // longer than a tick, so that the clock sees it even without the TSC
+    executor.set_poll_budget(Some(Duration::from_millis(1)));
+    executor.spawn(async { time::busy_wait(Duration::from_millis(3)) });
+    executor.spawn(async {});
+    executor.run_ready_tasks();
+    assert_eq!(executor.stats().long_polls, 1);
+
+    let (sender, receiver) = super::sync::oneshot::channel::<()>();
+    executor.spawn_task(Task::named("slow", async move {
+        time::busy_wait(Duration::from_millis(3));
+        receiver.await.ok();
+    }));
+    executor.run_ready_tasks();
+    let list = executor.task_list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].stats.long_polls, 1);
+    drop(sender);
+    executor.run_ready_tasks();
+    assert_eq!(executor.stats().long_polls, 2);
+}
@@ -0,0 +1,202 @@
+use super::coop;
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+/// Error returned by `IrqChannel::send`, with the value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The queue is full, the receiver doesn't keep up
+    Full(T),
+    /// No receiver exists, nobody would read the value
+    Closed(T),
+}
+
+/// Counters of an `IrqChannel`, see `IrqChannel::stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IrqChannelStats {
+    /// Values queued for the receiver
+    pub sent: u64,
+    /// Values dropped because the queue was full
+    pub overflows: u64,
+    /// Values dropped because no receiver existed
+    pub unreceived: u64,
+}
+
+/// Fixed-size queue carrying values from an interrupt handler to a task.
+///
+/// Channels are meant to be statics, one per driver. The queue is allocated when the first
+/// receiver is created, after which sending never allocates nor blocks, so interrupt handlers can
+/// use it. There is at most one receiver at a time, a `Stream` of the values.
+pub struct IrqChannel<T> {
+    capacity: usize,
+    queue: OnceCell<ArrayQueue<T>>,
+    /// Wakes the receiver when a value is sent
+    waker: AtomicWaker,
+    receiver_alive: AtomicBool,
+    sent: AtomicU64,
+    overflows: AtomicU64,
+    unreceived: AtomicU64,
+}
+
+impl<T> IrqChannel<T> {
+    /// Create a channel queueing up to `capacity` values. No memory is allocated yet.
+    pub const fn new(capacity: usize) -> Self {
+        IrqChannel {
+            capacity,
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            receiver_alive: AtomicBool::new(false),
+            sent: AtomicU64::new(0),
+            overflows: AtomicU64::new(0),
+            unreceived: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `value` and wake the receiver. Called by interrupt handlers.
+    ///
+    /// Must not block or allocate.
+    pub fn send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let queue = match self.queue.try_get() {
+            Ok(queue) if self.receiver_alive.load(Ordering::Acquire) => queue,
+            _ => {
+                self.unreceived.fetch_add(1, Ordering::Relaxed);
+                return Err(TrySendError::Closed(value));
+            }
+        };
+        match queue.push(value) {
+            Ok(()) => {
+                self.sent.fetch_add(1, Ordering::Relaxed);
+                self.waker.wake();
+                Ok(())
+            }
+            Err(crossbeam_queue::PushError(value)) => {
+                self.overflows.fetch_add(1, Ordering::Relaxed);
+                Err(TrySendError::Full(value))
+            }
+        }
+    }
+
+    /// Counters of the values sent and dropped since boot
+    pub fn stats(&self) -> IrqChannelStats {
+        IrqChannelStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            overflows: self.overflows.load(Ordering::Relaxed),
+            unreceived: self.unreceived.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Maximum number of values queued
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl<T: 'static> IrqChannel<T> {
+    /// Start receiving values, allocating the queue on first use.
+    ///
+    /// Returns `None` while another receiver exists. Values sent while no receiver existed were
+    /// dropped.
+    pub fn receiver(&'static self) -> Option<IrqReceiver<T>> {
+        if self.receiver_alive.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        self.queue
+            .try_init_once(|| ArrayQueue::new(self.capacity))
+            .ok();
+        Some(IrqReceiver { channel: self })
+    }
+}
+
+/// Receiving side of an `IrqChannel`, an endless `Stream` of the values sent
+pub struct IrqReceiver<T: 'static> {
+    channel: &'static IrqChannel<T>,
+}
+
+impl<T> IrqReceiver<T> {
+    /// Take the next value if one is queued.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.queue().pop().ok()
+    }
+
+    /// Wait for the next value.
+    pub async fn recv(&mut self) -> T {
+        futures_util::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Poll for the next value, like `recv`. Spends the task's budget, see `task::coop`.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<T> {
+        coop::poll_budgeted(cx, |cx| self.poll_queue(cx))
+    }
+
+    fn poll_queue(&mut self, cx: &mut Context) -> Poll<T> {
+        // fast path
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(value);
+        }
+
+        self.channel.waker.register(cx.waker());
+        match self.try_recv() {
+            Some(value) => {
+                self.channel.waker.take();
+                Poll::Ready(value)
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn queue(&self) -> &ArrayQueue<T> {
+        self.channel
+            .queue
+            .try_get()
+            .expect("irq channel queue uninitialized")
+    }
+}
+
+impl<T> Stream for IrqReceiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx).map(Some)
+    }
+}
+
+impl<T> Drop for IrqReceiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_alive.store(false, Ordering::Release);
+        // the next receiver only gets the values sent after its creation
+        while self.try_recv().is_some() {}
+        self.channel.waker.take();
+    }
+}
+
+/// Values reach the receiver in order, and dropped values are counted
+#[test_case]
+fn test_irq_channel() {
+    use futures_util::FutureExt;
+    use futures_util::StreamExt;
+
+    static CHANNEL: IrqChannel<u32> = IrqChannel::new(2);
+    assert_eq!(CHANNEL.send(0), Err(TrySendError::Closed(0)));
+
+    let mut receiver = CHANNEL.receiver().expect("no receiver yet");
+    assert!(CHANNEL.receiver().is_none());
+    assert_eq!(receiver.next().now_or_never(), None);
+    for value in 1..4 {
+        let _ = CHANNEL.send(value);
+    }
+    assert_eq!(receiver.next().now_or_never(), Some(Some(1)));
+    assert_eq!(receiver.try_recv(), Some(2));
+    assert_eq!(receiver.try_recv(), None);
+    let stats = CHANNEL.stats();
+    assert_eq!((stats.sent, stats.overflows, stats.unreceived), (2, 1, 1));
+
+    let _ = CHANNEL.send(4);
+    drop(receiver);
+    let mut receiver = CHANNEL.receiver().expect("receiver dropped");
+    assert_eq!(receiver.try_recv(), None);
+}
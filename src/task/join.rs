@@ -0,0 +1,139 @@
+use super::AbortHandle;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Reason why a `JoinHandle` has no value to return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was dropped before completing, e.g. along with its executor
+    Cancelled,
+}
+
+enum Slot<T> {
+    Running,
+    Finished(T),
+    Cancelled,
+    /// The output was returned by the handle
+    Taken,
+}
+
+struct Shared<T> {
+    slot: Slot<T>,
+    /// Waker of the task awaiting the handle
+    waker: Option<Waker>,
+}
+
+impl<T> Shared<T> {
+    /// Fill the slot if the task is still running, returning the waker to notify.
+    fn complete(&mut self, slot: Slot<T>) -> Option<Waker> {
+        if let Slot::Running = self.slot {
+            self.slot = slot;
+            self.waker.take()
+        } else {
+            None
+        }
+    }
+}
+
+/// Handle to the output of a spawned task.
+///
+/// It is a future resolving to the output, or to `JoinError::Cancelled` if the task was dropped
+/// before completing. Dropping the handle detaches the task, which keeps running.
+pub struct JoinHandle<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    abort: Option<AbortHandle>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn with_abort_handle(self, abort: AbortHandle) -> Self {
+        JoinHandle {
+            abort: Some(abort),
+            ..self
+        }
+    }
+
+    /// Handle to stop the task
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone().expect("JoinHandle without task")
+    }
+
+    /// Stop the task, see `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort_handle().abort();
+    }
+
+    /// Whether the task completed or was cancelled
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.shared.lock().slot, Slot::Running)
+    }
+
+    /// Whether the task was dropped before completing
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.shared.lock().slot, Slot::Cancelled)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock();
+        match core::mem::replace(&mut shared.slot, Slot::Taken) {
+            Slot::Running => {
+                shared.slot = Slot::Running;
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Slot::Finished(value) => Poll::Ready(Ok(value)),
+            Slot::Cancelled => Poll::Ready(Err(JoinError::Cancelled)),
+            Slot::Taken => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+/// Marks the task as cancelled if dropped before `finish` is called
+struct Completion<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Completion<T> {
+    fn finish(self, value: T) {
+        let waker = self.0.lock().complete(Slot::Finished(value));
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let waker = self.0.lock().complete(Slot::Cancelled);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Wrap `future` into one returning `()`, which hands its output to the returned handle.
+pub(super) fn joinable<F>(future: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let shared = Arc::new(Mutex::new(Shared {
+        slot: Slot::Running,
+        waker: None,
+    }));
+    let completion = Completion(shared.clone());
+    let task = async move {
+        let value = future.await;
+        completion.finish(value);
+    };
+    (
+        task,
+        JoinHandle {
+            shared,
+            abort: None,
+        },
+    )
+}
@@ -1,42 +1,101 @@
+use super::irq_channel::{IrqChannel, IrqReceiver, TrySendError};
 use crate::println;
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
+use alloc::vec::Vec;
+use spin::Mutex;
 
 use core::{
     pin::Pin,
-    task::{Context, Poll},
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
 };
 use futures_util::stream::Stream;
 use futures_util::stream::StreamExt;
-use futures_util::task::AtomicWaker;
 
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
 use crate::print;
 
-/// Statically-allocated fixed-size queue of scancodes
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+/// Scancodes from the keyboard interrupt handler, see `IrqChannel::stats` for dropped inputs
+pub static SCANCODES: IrqChannel<u8> = IrqChannel::new(100);
 
-/// Statically-allocated waker to notify the executor when a new scancode is available
-static WAKER: AtomicWaker = AtomicWaker::new();
+struct Subscriber {
+    id: u64,
+    /// Waker of the last poll, woken when the subscriber gets the focus
+    waker: Option<Waker>,
+}
+
+/// Subscribers to the keyboard, the last one having the focus
+struct Subscribers {
+    list: Vec<Subscriber>,
+    /// Receiver of `SCANCODES`, held while there are subscribers
+    receiver: Option<IrqReceiver<u8>>,
+}
+
+impl Subscribers {
+    fn focused(&self) -> Option<u64> {
+        self.list.last().map(|subscriber| subscriber.id)
+    }
+
+    /// Waker of the focused subscriber, to call once the lock is released
+    fn focused_waker(&mut self) -> Option<Waker> {
+        self.list.last_mut()?.waker.take()
+    }
+}
 
-/// Stream of scancodes (Singleton)
+static SUBSCRIBERS: Mutex<Subscribers> = Mutex::new(Subscribers {
+    list: Vec::new(),
+    receiver: None,
+});
+
+/// Stream of scancodes, one per subscriber of the keyboard.
+///
+/// Only the subscriber with the focus receives the keyboard input, the others wait. A new stream
+/// takes the focus, and when the focused stream is dropped the focus goes back to the one that
+/// had it before.
 pub struct ScancodeStream {
-    /// Prevents construction from outside the module.
-    _private: (),
+    id: u64,
 }
 
 impl ScancodeStream {
-    /// Initializes the scancode queue.
+    /// Subscribe to the keyboard, taking the focus.
     ///
-    /// # Panics
-    /// Should only be called once.
+    /// The scancode queue is allocated on first use.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut subscribers = SUBSCRIBERS.lock();
+        if subscribers.receiver.is_none() {
+            let receiver = SCANCODES.receiver().expect("scancodes received elsewhere");
+            subscribers.receiver = Some(receiver);
+        }
+        subscribers.list.push(Subscriber { id, waker: None });
+        ScancodeStream { id }
+    }
+
+    /// Whether this stream receives the keyboard input
+    pub fn has_focus(&self) -> bool {
+        SUBSCRIBERS.lock().focused() == Some(self.id)
+    }
+
+    /// Take the focus from the other subscribers.
+    pub fn focus(&self) {
+        let waker = {
+            let mut subscribers = SUBSCRIBERS.lock();
+            let list = &mut subscribers.list;
+            match list.iter().position(|subscriber| subscriber.id == self.id) {
+                Some(position) => {
+                    let subscriber = list.remove(position);
+                    list.push(subscriber);
+                }
+                None => return,
+            }
+            subscribers.focused_waker()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
     }
 }
 
@@ -44,24 +103,39 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("SCANCODE_QUEUE uninitialized");
-
-        // fast path
-        if let Ok(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
+        let mut subscribers = SUBSCRIBERS.lock();
+        if subscribers.focused() == Some(self.id) {
+            let receiver = subscribers.receiver.as_mut();
+            let receiver = receiver.expect("subscribers without scancode receiver");
+            if let Poll::Ready(scancode) = receiver.poll_recv(cx) {
+                return Poll::Ready(Some(scancode));
+            }
+        }
+        let list = &mut subscribers.list;
+        if let Some(subscriber) = list.iter_mut().find(|subscriber| subscriber.id == self.id) {
+            subscriber.waker = Some(cx.waker().clone());
         }
+        Poll::Pending
+    }
+}
 
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            // Scancode found
-            Ok(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
+impl Drop for ScancodeStream {
+    fn drop(&mut self) {
+        let (waker, receiver) = {
+            let mut subscribers = SUBSCRIBERS.lock();
+            subscribers
+                .list
+                .retain(|subscriber| subscriber.id != self.id);
+            if subscribers.list.is_empty() {
+                (None, subscribers.receiver.take())
+            } else {
+                (subscribers.focused_waker(), None)
             }
-            // Missing scancode, queue empty
-            Err(crossbeam_queue::PopError) => Poll::Pending,
+        };
+        // without subscribers, the keyboard input is dropped instead of queued
+        drop(receiver);
+        if let Some(waker) = waker {
+            waker.wake();
         }
     }
 }
@@ -70,21 +144,16 @@ impl Stream for ScancodeStream {
 ///
 /// Must not block or allocated.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if queue.push(scancode).is_err() {
-            println!(
-                "WARNING: scancode queue full; dropping keyboard input {}",
-                scancode
-            );
-        } else {
-            // notify the executor that a new scancode is available
-            WAKER.wake();
-        }
-    } else {
-        println!(
-            "WARNING: scancode queue uninitialized; dropping keyboard input {}",
+    match SCANCODES.send(scancode) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => println!(
+            "WARNING: scancode queue full; dropping keyboard input {}",
+            scancode
+        ),
+        Err(TrySendError::Closed(_)) => println!(
+            "WARNING: no scancode stream; dropping keyboard input {}",
             scancode
-        );
+        ),
     }
 }
 
@@ -106,3 +175,32 @@ pub async fn print_keypresses() {
         }
     }
 }
+
+/// Only the focused subscriber receives scancodes, and the focus goes back when it is dropped
+#[test_case]
+fn test_subscribers() {
+    use futures_util::FutureExt;
+
+    let mut first = ScancodeStream::new();
+    let mut second = ScancodeStream::new();
+    assert!(second.has_focus());
+    SCANCODES.send(1).unwrap();
+    assert_eq!(first.next().now_or_never(), None);
+    assert_eq!(second.next().now_or_never(), Some(Some(1)));
+
+    first.focus();
+    SCANCODES.send(2).unwrap();
+    assert_eq!(second.next().now_or_never(), None);
+    assert_eq!(first.next().now_or_never(), Some(Some(2)));
+
+    drop(first);
+    assert!(second.has_focus());
+    SCANCODES.send(3).unwrap();
+    assert_eq!(second.next().now_or_never(), Some(Some(3)));
+
+    drop(second);
+    assert_eq!(SCANCODES.send(4), Err(TrySendError::Closed(4)));
+    let mut again = ScancodeStream::new();
+    SCANCODES.send(5).unwrap();
+    assert_eq!(again.next().now_or_never(), Some(Some(5)));
+}
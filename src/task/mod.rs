@@ -1,34 +1,139 @@
+pub use abort::AbortHandle;
+use abort::AbortState;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+pub use combinators::{join_all, race, timeout, timeout_at};
+pub use coop::yield_now;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
+pub use join::{JoinError, JoinHandle};
 
-/// Contains a very simple executor, executing each task sequentially until all are done
+/// Deterministic executor for tests, with deadlock detection and virtual time
 pub mod simple_executor;
 
 /// Simple single-threaded executor with waker support
 pub mod executor;
 
+/// Poll counts and CPU time of the executor and its tasks
+pub mod stats;
+
+/// Handles to the output of spawned tasks
+pub mod join;
+
+/// Aborting spawned tasks
+pub mod abort;
+
+/// Async synchronization primitives
+pub mod sync;
+
+/// Channels from interrupt handlers to tasks
+pub mod irq_channel;
+
 /// Async keyboard driver
 pub mod keyboard;
 
+/// Timer futures, driven by the timer interrupt
+pub mod timer;
+
+/// Cooperative scheduling budget and `yield_now`
+pub mod coop;
+
+/// Timeouts, races and joins of futures
+pub mod combinators;
+
 /// A task that contains a future returning ()
 pub struct Task {
     id: TaskID,
+    name: Option<&'static str>,
+    priority: Priority,
+    abort: Arc<AbortState>,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
+/// Scheduling priority of a task: the executor polls ready tasks of higher priority first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Background work
+    Low,
+    /// Priority of tasks created by `Task::new`
+    #[default]
+    Normal,
+    /// Latency sensitive tasks, like input handling
+    High,
+}
+
+impl Priority {
+    /// Number of priority levels
+    pub const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
 impl Task {
-    /// Create a new task from a Future.
+    /// Create a new task from a Future, with normal priority.
     pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Self::with_priority(future, Priority::Normal)
+    }
+
+    /// Create a new task from a Future, with normal priority and a name shown in task listings.
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Self {
+        Self::new(future).with_name(name)
+    }
+
+    /// Create a new task from a Future, with the given priority.
+    pub fn with_priority(future: impl Future<Output = ()> + 'static, priority: Priority) -> Self {
         // The 'static lifetime is required here because the returned Task can live for
         // an arbitrary time, so the future needs to be valid for that time too
         Self {
             id: TaskID::new(),
+            name: None,
+            priority,
+            abort: AbortState::new(),
             future: Box::pin(future),
         }
     }
 
+    /// Create a task running `future`, along with a handle to its output.
+    pub fn joinable<F>(future: F, priority: Priority) -> (Self, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+    {
+        let (future, handle) = join::joinable(future);
+        let task = Self::with_priority(future, priority);
+        let handle = handle.with_abort_handle(task.abort_handle());
+        (task, handle)
+    }
+
+    /// Name this task, for task listings.
+    pub fn with_name(self, name: &'static str) -> Self {
+        Self {
+            name: Some(name),
+            ..self
+        }
+    }
+
+    /// Name of this task, if it was given one
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Priority of this task
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Handle to stop this task once spawned
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle::new(self.abort.clone())
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.abort.is_aborted()
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
@@ -1,35 +1,187 @@
-use super::Task;
-use alloc::collections::VecDeque;
-use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use super::executor::{RunQueue, TaskWaker};
+use super::timer::VirtualClock;
+use super::{coop, Task, TaskID};
+use crate::time::Instant;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::Ordering;
+use core::task::{Context, Waker};
 
-/// Very simple executor, executing each task sequentially until all are done.
+/// Deterministic single-threaded executor, for tests.
+///
+/// Tasks are polled one at a time, in the order they were woken. Once no task is ready, the
+/// executor looks for tasks that can never be woken since nobody holds their waker anymore, and
+/// reports them as a `Deadlock`. With virtual time, it then moves its clock to the next deadline
+/// among the timers of its tasks instead of waiting for it.
 pub struct SimpleExecutor {
-    task_queue: VecDeque<Task>,
+    tasks: BTreeMap<TaskID, Task>,
+    wakers: BTreeMap<TaskID, Arc<TaskWaker>>,
+    task_queue: Arc<RunQueue>,
+    clock: Option<Arc<VirtualClock>>,
+}
+
+/// Task stalled forever, see `Deadlock`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StalledTask {
+    /// Unique id of the task
+    pub id: u64,
+    /// Name given with `Task::named` or `Task::with_name`
+    pub name: Option<&'static str>,
+}
+
+/// Error returned by `SimpleExecutor::run` when tasks are pending without any way to be woken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deadlock {
+    /// Pending tasks whose waker nobody holds, ordered by id
+    pub tasks: Vec<StalledTask>,
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadlock, pending without waker:")?;
+        for task in &self.tasks {
+            write!(f, " task {} {}", task.id, task.name.unwrap_or("<unnamed>"))?;
+        }
+        Ok(())
+    }
 }
 
 impl SimpleExecutor {
     /// Create new Executor with no queued tasks
     pub fn new() -> Self {
         Self {
-            task_queue: VecDeque::new(),
+            tasks: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+            task_queue: RunQueue::new(),
+            clock: None,
+        }
+    }
+
+    /// Create an executor running on virtual time.
+    ///
+    /// Timers created by its tasks follow a clock of its own, frozen at the current time, which
+    /// only moves forward when every task waits, see `timer::now`. Timers created elsewhere, and
+    /// the rest of the kernel, keep the real time.
+    pub fn with_virtual_time() -> Self {
+        Self {
+            clock: Some(VirtualClock::new()),
+            ..Self::new()
         }
     }
 
+    /// Current time as seen by the tasks: the virtual clock if the executor has one
+    pub fn now(&self) -> Instant {
+        self.clock
+            .as_ref()
+            .map_or_else(Instant::now, |clock| clock.now())
+    }
+
     /// Add given task to the queue
     pub fn spawn(&mut self, task: Task) {
-        self.task_queue.push_back(task)
+        let task_id = task.id;
+        // every task may be queued at once
+        self.task_queue.reserve(self.tasks.len() + 1);
+        let waker = TaskWaker::new(task_id, self.task_queue.clone());
+        task.abort.register(&Waker::from(waker.clone()));
+        if self.tasks.insert(task_id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        waker.wake_task();
+        self.wakers.insert(task_id, waker);
+    }
+
+    /// Poll the ready tasks until none is left, returning the number of tasks not done yet.
+    ///
+    /// Aborted tasks are dropped when they are polled, or here once no task is ready.
+    pub fn run_until_stalled(&mut self) -> usize {
+        while let Some(task_id) = self.task_queue.pop() {
+            let task = match self.tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+            let task_waker = &self.wakers[&task_id];
+            if !task.is_aborted() {
+                task_waker.scheduled.store(false, Ordering::Release);
+                let waker = Waker::from(task_waker.clone());
+                let mut context = Context::from_waker(&waker);
+                coop::start_poll();
+                let poll = match &self.clock {
+                    Some(clock) => clock.enter(|| task.poll(&mut context)),
+                    None => task.poll(&mut context),
+                };
+                coop::end_poll();
+                if poll.is_pending() {
+                    continue;
+                }
+            }
+            // task done or aborted, dropping it cancels it
+            self.tasks.remove(&task_id);
+            if let Some(task_waker) = self.wakers.remove(&task_id) {
+                task_waker.retire();
+            }
+        }
+
+        let aborted: Vec<TaskID> = self
+            .tasks
+            .iter()
+            .filter(|(_, task)| task.is_aborted())
+            .map(|(&task_id, _)| task_id)
+            .collect();
+        for task_id in aborted {
+            self.tasks.remove(&task_id);
+            if let Some(task_waker) = self.wakers.remove(&task_id) {
+                task_waker.retire();
+            }
+        }
+        self.tasks.len()
     }
 
-    /// Run all tasks in the queue, until everything is done
-    pub fn run(&mut self) {
-        while let Some(mut task) = self.task_queue.pop_front() {
-            let waker = dummy_waker();
-            let mut context = Context::from_waker(&waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {} // Task done
-                Poll::Pending => self.task_queue.push_back(task),
+    /// Pending tasks that can never be woken, since only the executor holds their waker
+    pub fn deadlocked(&self) -> Vec<StalledTask> {
+        self.wakers
+            .iter()
+            .filter(|(_, waker)| {
+                !waker.scheduled.load(Ordering::Acquire) && Arc::strong_count(waker) == 1
+            })
+            .map(|(task_id, _)| StalledTask {
+                id: task_id.0,
+                name: self.tasks[task_id].name,
+            })
+            .collect()
+    }
+
+    /// Run all tasks until everything is done.
+    ///
+    /// While every task waits, the CPU halts until an interrupt wakes one. With virtual time, the
+    /// clock first moves to the next deadline among the timers of the tasks, if they have any.
+    /// Fails if a task can never be woken.
+    pub fn run(&mut self) -> Result<(), Deadlock> {
+        use x86_64::instructions::interrupts;
+
+        while self.run_until_stalled() > 0 {
+            let deadlocked = self.deadlocked();
+            if !deadlocked.is_empty() {
+                return Err(Deadlock { tasks: deadlocked });
+            }
+            let next_deadline = self
+                .clock
+                .as_ref()
+                .and_then(|clock| Some((clock, clock.next_deadline()?)));
+            match next_deadline {
+                Some((clock, deadline)) => clock.advance(deadline),
+                None => {
+                    interrupts::disable();
+                    if self.task_queue.is_empty() {
+                        interrupts::enable_and_hlt();
+                    } else {
+                        interrupts::enable();
+                    }
+                }
             }
         }
+        Ok(())
     }
 }
 
@@ -39,17 +191,92 @@ impl Default for SimpleExecutor {
     }
 }
 
-fn dummy_raw_waker() -> RawWaker {
-    fn noop(_: *const ()) {}
-    fn clone(_: *const ()) -> RawWaker {
-        dummy_raw_waker()
-    }
+/// Wakers requeue their task, and the executor only stalls once every task waits
+#[test_case]
+fn test_run_until_stalled() {
+    let mut executor = SimpleExecutor::new();
+    let (sender, receiver) = super::sync::oneshot::channel();
+    executor.spawn(Task::new(async move {
+        assert_eq!(receiver.await, Ok(7));
+    }));
+    assert_eq!(executor.run_until_stalled(), 1);
+    assert!(executor.deadlocked().is_empty());
+    assert_eq!(executor.run_until_stalled(), 1);
+    sender.send(7).unwrap();
+    assert_eq!(executor.run_until_stalled(), 0);
+}
 
-    let vtable = &RawWakerVTable::new(clone, noop, noop, noop);
-    #[allow(clippy::zero_ptr)] // due to no_std, we cant use std::ptr::null
-    RawWaker::new(0 as *const (), vtable)
+/// Tasks nobody can wake are reported
+#[test_case]
+fn test_deadlock() {
+    let mut executor = SimpleExecutor::new();
+    let (sender, receiver) = super::sync::oneshot::channel::<()>();
+    executor.spawn(Task::named("stuck", futures_util::future::pending()));
+    executor.spawn(Task::new(async move {
+        receiver.await.ok();
+    }));
+    let error = executor.run().unwrap_err();
+    assert_eq!(error.tasks.len(), 1);
+    assert_eq!(error.tasks[0].name, Some("stuck"));
+    drop(sender);
 }
 
-fn dummy_waker() -> Waker {
-    unsafe { Waker::from_raw(dummy_raw_waker()) }
+/// Aborting a pending task wakes the executor, which drops it instead of reporting a deadlock
+#[test_case]
+fn test_abort() {
+    let mut executor = SimpleExecutor::new();
+    let task = Task::named("stuck", futures_util::future::pending());
+    let handle = task.abort_handle();
+    executor.spawn(task);
+    assert_eq!(executor.run_until_stalled(), 1);
+    assert!(executor.deadlocked().is_empty());
+    handle.abort();
+    assert_eq!(executor.run(), Ok(()));
+}
+
+/// Virtual time skips to the next deadline of the tasks' timers instead of waiting for it
+#[test_case]
+fn test_virtual_time() {
+    use super::timer;
+    use alloc::vec;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::time::Duration;
+    use spin::Mutex;
+
+    // a timer of the rest of the kernel, which must keep the real time
+    let mut outside = timer::sleep(Duration::from_secs(5));
+    assert!(Pin::new(&mut outside)
+        .poll(&mut Context::from_waker(Waker::noop()))
+        .is_pending());
+
+    let real_start = Instant::now();
+    let order = Arc::new(Mutex::new(vec![]));
+    let mut executor = SimpleExecutor::with_virtual_time();
+    let start = executor.now();
+    for &seconds in &[30, 10, 20] {
+        let order = order.clone();
+        executor.spawn(Task::new(async move {
+            timer::sleep(Duration::from_secs(seconds)).await;
+            order.lock().push((seconds, timer::now()));
+        }));
+    }
+    executor.run().unwrap();
+    let order = order.lock();
+    assert_eq!(
+        order
+            .iter()
+            .map(|&(seconds, _)| seconds)
+            .collect::<Vec<_>>(),
+        [10, 20, 30]
+    );
+    for &(seconds, woken_at) in order.iter() {
+        assert!(woken_at - start >= Duration::from_secs(seconds));
+    }
+    assert!(executor.now() - start >= Duration::from_secs(30));
+
+    assert!(real_start.elapsed() < Duration::from_secs(1));
+    assert!(timer::now() - Instant::now() < Duration::from_secs(1));
+    assert!(!outside.is_elapsed());
+    assert_eq!(timer::pending_timers(), 1);
 }
@@ -0,0 +1,61 @@
+use crate::time::tsc;
+use core::time::Duration;
+
+/// Activity of a single task since it was spawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TaskStats {
+    /// Number of times the task was polled
+    pub polls: u64,
+    /// Number of times its waker was woken, including wakeups while it was already queued
+    pub wakeups: u64,
+    /// TSC cycles spent polling the task
+    pub cpu_cycles: u64,
+    /// Number of polls that took longer than the executor's poll budget
+    pub long_polls: u64,
+}
+
+impl TaskStats {
+    /// Time spent polling the task, or `None` if the TSC is not used as a clock
+    pub fn cpu_time(&self) -> Option<Duration> {
+        tsc::cycles_to_duration(self.cpu_cycles)
+    }
+}
+
+/// Activity of an executor, summed over every task it ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExecutorStats {
+    /// Tasks handed to the executor
+    pub spawned: u64,
+    /// Tasks that ran to completion
+    pub completed: u64,
+    /// Tasks dropped after being aborted
+    pub aborted: u64,
+    /// Number of polls of all tasks
+    pub polls: u64,
+    /// Number of wakeups of all tasks
+    pub wakeups: u64,
+    /// TSC cycles spent polling tasks
+    pub cpu_cycles: u64,
+    /// Number of polls that took longer than the poll budget
+    pub long_polls: u64,
+}
+
+impl ExecutorStats {
+    /// Tasks currently owned by the executor
+    pub fn live(&self) -> u64 {
+        self.spawned - self.completed - self.aborted
+    }
+
+    /// Time spent polling tasks, or `None` if the TSC is not used as a clock
+    pub fn cpu_time(&self) -> Option<Duration> {
+        tsc::cycles_to_duration(self.cpu_cycles)
+    }
+
+    /// Add the activity of a task.
+    pub(super) fn add(&mut self, task: &TaskStats) {
+        self.polls += task.polls;
+        self.wakeups += task.wakeups;
+        self.cpu_cycles += task.cpu_cycles;
+        self.long_polls += task.long_polls;
+    }
+}
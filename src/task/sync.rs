@@ -0,0 +1,27 @@
+//! Synchronization primitives for async tasks.
+//!
+//! Instead of spinning, waiting tasks return `Poll::Pending` and are woken through their `Waker`
+//! once they can make progress, so the executor keeps running other tasks meanwhile. Waiters are
+//! served in FIFO order.
+//!
+//! Their internal state is protected by short critical sections on spin locks. Except for
+//! `Notify::notify_one` and `Notify::notify_waiters`, they must not be used from interrupt
+//! handlers.
+
+/// Multi-producer, single-consumer channels
+pub mod mpsc;
+/// Async mutex
+pub mod mutex;
+/// Wake tasks waiting for an event
+pub mod notify;
+/// Single value channel
+pub mod oneshot;
+/// Async reader-writer lock
+pub mod rwlock;
+/// Counting semaphore
+pub mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::Notify;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{AcquireError, Semaphore, SemaphorePermit};
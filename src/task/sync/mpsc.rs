@@ -0,0 +1,321 @@
+use super::Semaphore;
+use crate::task::coop;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::Stream;
+use spin::Mutex;
+
+/// Error returned when sending to a channel whose receiver was dropped, with the value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Error returned by `Sender::try_send`, with the value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity
+    Full(T),
+    /// The receiver was dropped
+    Closed(T),
+}
+
+/// Error returned by `Receiver::try_recv`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No value is queued right now
+    Empty,
+    /// No value is queued and every sender was dropped
+    Disconnected,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+}
+
+struct Chan<T> {
+    state: Mutex<State<T>>,
+    /// Free slots of a bounded channel, closed along with the receiver
+    capacity: Option<Semaphore>,
+}
+
+impl<T> Chan<T> {
+    fn new(capacity: Option<usize>) -> Arc<Self> {
+        Arc::new(Chan {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                senders: 1,
+                receiver_alive: true,
+                receiver_waker: None,
+            }),
+            capacity: capacity.map(Semaphore::new),
+        })
+    }
+
+    /// Queue `value`, once a slot was taken for it in a bounded channel.
+    fn push(&self, value: T) -> Result<(), SendError<T>> {
+        let waker = {
+            let mut state = self.state.lock();
+            if !state.receiver_alive {
+                return Err(SendError(value));
+            }
+            state.queue.push_back(value);
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn add_sender(&self) {
+        self.state.lock().senders += 1;
+    }
+
+    fn drop_sender(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.senders -= 1;
+            if state.senders > 0 {
+                return;
+            }
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        !self.state.lock().receiver_alive
+    }
+}
+
+/// Create a channel holding up to `capacity` values, senders waiting when it is full.
+///
+/// # Panics
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be non-zero");
+    let chan = Chan::new(Some(capacity));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Create a channel without capacity limit, on which sending never waits.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Sending half of a bounded channel, created by `channel`
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    fn capacity(&self) -> &Semaphore {
+        self.chan
+            .capacity
+            .as_ref()
+            .expect("bounded channel without capacity")
+    }
+
+    /// Wait for a free slot, then queue `value`.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.capacity().acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return Err(SendError(value)),
+        }
+        self.chan.push(value)
+    }
+
+    /// Queue `value` if there is a free slot.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.capacity().try_acquire() {
+            Some(permit) => permit.forget(),
+            None if self.is_closed() => return Err(TrySendError::Closed(value)),
+            None => return Err(TrySendError::Full(value)),
+        }
+        self.chan
+            .push(value)
+            .map_err(|SendError(value)| TrySendError::Closed(value))
+    }
+
+    /// Whether the receiver was dropped
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+/// Sending half of an unbounded channel, created by `unbounded_channel`
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    /// Queue `value`.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.push(value)
+    }
+
+    /// Whether the receiver was dropped
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        UnboundedSender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+/// Receiving half of a channel
+///
+/// It is also a `Stream` of the values, ending once every sender was dropped.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the next value, or `None` once the queue is empty and every sender was dropped.
+    pub async fn recv(&mut self) -> Option<T> {
+        futures_util::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Take the next value if one is queued.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let (value, disconnected) = {
+            let mut state = self.chan.state.lock();
+            (state.queue.pop_front(), state.senders == 0)
+        };
+        match value {
+            Some(value) => {
+                self.free_slot();
+                Ok(value)
+            }
+            None if disconnected => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Poll for the next value, like `recv`. Spends the task's budget, see `task::coop`.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        coop::poll_budgeted(cx, |cx| self.poll_queue(cx))
+    }
+
+    fn poll_queue(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let value = {
+            let mut state = self.chan.state.lock();
+            match state.queue.pop_front() {
+                Some(value) => value,
+                None if state.senders == 0 => return Poll::Ready(None),
+                None => {
+                    state.receiver_waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        self.free_slot();
+        Poll::Ready(Some(value))
+    }
+
+    /// Stop accepting values. Those already queued can still be received.
+    pub fn close(&mut self) {
+        self.chan.state.lock().receiver_alive = false;
+        if let Some(capacity) = &self.chan.capacity {
+            capacity.close();
+        }
+    }
+
+    fn free_slot(&self) {
+        if let Some(capacity) = &self.chan.capacity {
+            capacity.add_permits(1);
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+        // the values' destructors run outside of the lock
+        let queue = core::mem::take(&mut self.chan.state.lock().queue);
+        drop(queue);
+    }
+}
+
+/// A bounded channel makes the sender wait for the receiver, and ends with the senders
+#[test_case]
+fn test_bounded_channel() {
+    use crate::task::executor::Executor;
+    use alloc::vec::Vec;
+
+    let (sender, mut receiver) = channel(1);
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    executor.spawn(async move {
+        for value in 0..3 {
+            sender.send(value).await.unwrap();
+        }
+        assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+    });
+    let output = received.clone();
+    executor.spawn(async move {
+        while let Some(value) = receiver.recv().await {
+            output.lock().push(value);
+        }
+    });
+    executor.run_ready_tasks();
+    assert_eq!(*received.lock(), [0, 1, 2]);
+}
+
+/// Unbounded sends never wait, and fail once the receiver is gone
+#[test_case]
+fn test_unbounded_channel() {
+    let (sender, mut receiver) = unbounded_channel();
+    for value in 0..200 {
+        sender.send(value).unwrap();
+    }
+    let other = sender.clone();
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Ok(0));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    drop(receiver);
+    assert!(other.is_closed());
+    assert_eq!(other.send(0), Err(SendError(0)));
+}
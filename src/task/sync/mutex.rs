@@ -0,0 +1,111 @@
+use super::Semaphore;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+/// Mutual exclusion lock whose `lock` waits asynchronously.
+///
+/// The guard can be held across `.await` points, unlike a spin lock which would deadlock the
+/// executor when another task tries to take it.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Create an unlocked mutex holding `value`
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consume the mutex, returning the value
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Wait until the lock is free, then take it.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        permit.expect("mutex semaphore closed").forget();
+        self.guard()
+    }
+
+    /// Take the lock if it is free and no task is waiting for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.semaphore.try_acquire()?.forget();
+        Some(self.guard())
+    }
+
+    /// The value, without locking since the mutex is borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Build the guard once the permit was taken.
+    fn guard(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            semaphore: &self.semaphore,
+            value: unsafe { &mut *self.value.get() },
+        }
+    }
+}
+
+/// Access to the value of a locked `Mutex`, which is unlocked when this is dropped
+pub struct MutexGuard<'a, T: ?Sized> {
+    semaphore: &'a Semaphore,
+    value: &'a mut T,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+/// A task waiting for the lock runs once the guard held across an await point is dropped
+#[test_case]
+fn test_mutex() {
+    use super::oneshot;
+    use crate::task::executor::Executor;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    let mutex = Arc::new(Mutex::new(Vec::new()));
+    let (sender, receiver) = oneshot::channel();
+    let mut executor = Executor::new();
+    let first = mutex.clone();
+    executor.spawn(async move {
+        let mut values = first.lock().await;
+        receiver.await.unwrap();
+        values.push(1);
+    });
+    let second = mutex.clone();
+    executor.spawn(async move { second.lock().await.push(2) });
+
+    executor.run_ready_tasks();
+    assert!(mutex.try_lock().is_none());
+    sender.send(()).unwrap();
+    executor.run_ready_tasks();
+    assert_eq!(*mutex.try_lock().unwrap(), [1, 2]);
+}
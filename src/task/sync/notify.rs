@@ -0,0 +1,195 @@
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    Waiting,
+    One,
+    All,
+}
+
+struct Waiter {
+    id: u64,
+    waker: Waker,
+    notification: Notification,
+}
+
+struct State {
+    /// Set by `notify_one` when no task was waiting
+    permit: bool,
+    waiters: VecDeque<Waiter>,
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        match self
+            .waiters
+            .iter_mut()
+            .find(|waiter| waiter.notification == Notification::Waiting)
+        {
+            Some(waiter) => {
+                waiter.notification = Notification::One;
+                waiter.waker.wake_by_ref();
+            }
+            None => self.permit = true,
+        }
+    }
+}
+
+/// Event on which tasks wait, without any data attached.
+///
+/// The notifying side doesn't allocate and locks with interrupts disabled, so interrupt handlers
+/// can use it to wake tasks.
+pub struct Notify {
+    /// Locked with interrupts disabled
+    state: Mutex<State>,
+}
+
+impl Notify {
+    /// Create an event with no task waiting and no stored notification
+    pub const fn new() -> Self {
+        Notify {
+            state: Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Wait for a notification.
+    ///
+    /// The task is only registered once the future is polled.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+
+    /// Wake the task waiting the longest. If no task is waiting, the next one to wait returns
+    /// immediately instead; notifications don't add up.
+    pub fn notify_one(&self) {
+        without_interrupts(|| self.state.lock().notify_one());
+    }
+
+    /// Wake every waiting task, without storing a notification for later ones.
+    pub fn notify_waiters(&self) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            for waiter in state.waiters.iter_mut() {
+                if waiter.notification == Notification::Waiting {
+                    waiter.notification = Notification::All;
+                    waiter.waker.wake_by_ref();
+                }
+            }
+        });
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+/// Future returned by `Notify::notified`
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// Set while registered among the waiters
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let this = &mut *self;
+        without_interrupts(|| {
+            let mut state = this.notify.state.lock();
+            let position = this
+                .id
+                .and_then(|id| state.waiters.iter().position(|waiter| waiter.id == id));
+            match position {
+                Some(position) if state.waiters[position].notification != Notification::Waiting => {
+                    state.waiters.remove(position);
+                    this.id = None;
+                    Poll::Ready(())
+                }
+                Some(position) => {
+                    state.waiters[position].waker = cx.waker().clone();
+                    Poll::Pending
+                }
+                None if state.permit => {
+                    state.permit = false;
+                    Poll::Ready(())
+                }
+                None => {
+                    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                    state.waiters.push_back(Waiter {
+                        id,
+                        waker: cx.waker().clone(),
+                        notification: Notification::Waiting,
+                    });
+                    this.id = Some(id);
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            without_interrupts(|| {
+                let mut state = self.notify.state.lock();
+                if let Some(position) = state.waiters.iter().position(|waiter| waiter.id == id) {
+                    let waiter = state.waiters.remove(position);
+                    // don't lose a notification meant for a single task
+                    if waiter.map(|waiter| waiter.notification) == Some(Notification::One) {
+                        state.notify_one();
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Notifications wake waiting tasks, or are stored for the next one
+#[test_case]
+fn test_notify() {
+    use futures_util::task::noop_waker_ref;
+    use futures_util::FutureExt;
+
+    let notify = Notify::new();
+    notify.notify_one();
+    notify.notify_one();
+    assert_eq!(notify.notified().now_or_never(), Some(()));
+    assert_eq!(notify.notified().now_or_never(), None);
+
+    let mut context = Context::from_waker(noop_waker_ref());
+    let mut first = notify.notified();
+    let mut second = notify.notified();
+    assert!(Pin::new(&mut first).poll(&mut context).is_pending());
+    assert!(Pin::new(&mut second).poll(&mut context).is_pending());
+    notify.notify_waiters();
+    assert!(Pin::new(&mut first).poll(&mut context).is_ready());
+    assert!(Pin::new(&mut second).poll(&mut context).is_ready());
+    assert_eq!(notify.notified().now_or_never(), None);
+
+    // a notification for a dropped waiter goes to the next one
+    let mut first = notify.notified();
+    let mut second = notify.notified();
+    assert!(Pin::new(&mut first).poll(&mut context).is_pending());
+    assert!(Pin::new(&mut second).poll(&mut context).is_pending());
+    notify.notify_one();
+    drop(first);
+    assert!(Pin::new(&mut second).poll(&mut context).is_ready());
+}
@@ -0,0 +1,126 @@
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Error returned by a `Receiver` whose `Sender` was dropped without sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct State<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+}
+
+/// Create a channel carrying a single value.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        receiver_waker: None,
+    }));
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+/// Sending half of a oneshot channel
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Send the value, or give it back if the receiver was dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waker = {
+            let mut state = self.state.lock();
+            if !state.receiver_alive {
+                return Err(value);
+            }
+            state.value = Some(value);
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Whether the receiver was dropped
+    pub fn is_closed(&self) -> bool {
+        !self.state.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.sender_alive = false;
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiving half of a oneshot channel, a future resolving to the value sent
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.value.take() {
+            Poll::Ready(Ok(value))
+        } else if !state.sender_alive {
+            Poll::Ready(Err(RecvError))
+        } else {
+            state.receiver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let value = {
+            let mut state = self.state.lock();
+            state.receiver_alive = false;
+            state.value.take()
+        };
+        // the value's destructor runs outside of the lock
+        drop(value);
+    }
+}
+
+/// The receiver gets the value, or an error if the sender is dropped
+#[test_case]
+fn test_oneshot() {
+    use futures_util::FutureExt;
+
+    let (sender, mut receiver) = channel();
+    assert!(Pin::new(&mut receiver).now_or_never().is_none());
+    sender.send(7).unwrap();
+    assert_eq!(receiver.now_or_never(), Some(Ok(7)));
+
+    let (sender, receiver) = channel::<()>();
+    drop(sender);
+    assert_eq!(receiver.now_or_never(), Some(Err(RecvError)));
+
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.send(7), Err(7));
+}
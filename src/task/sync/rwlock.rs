@@ -0,0 +1,153 @@
+use super::Semaphore;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+/// Permits of the semaphore: a reader takes one, a writer all of them
+const MAX_READERS: usize = u32::MAX as usize >> 3;
+
+/// Reader-writer lock whose `read` and `write` wait asynchronously.
+///
+/// Requests are served in order, so a waiting writer holds back the readers that come after it
+/// and can't be starved.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Create an unlocked lock holding `value`
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            semaphore: Semaphore::new(MAX_READERS),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consume the lock, returning the value
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Wait until no writer holds or waits for the lock, then take shared access.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        permit.expect("rwlock semaphore closed").forget();
+        self.read_guard()
+    }
+
+    /// Wait until the lock is free, then take exclusive access.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let permit = self.semaphore.acquire_many(MAX_READERS).await;
+        permit.expect("rwlock semaphore closed").forget();
+        self.write_guard()
+    }
+
+    /// Take shared access if no writer holds or waits for the lock.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore.try_acquire()?.forget();
+        Some(self.read_guard())
+    }
+
+    /// Take exclusive access if the lock is free and nobody waits for it.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore.try_acquire_many(MAX_READERS)?.forget();
+        Some(self.write_guard())
+    }
+
+    /// The value, without locking since the lock is borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn read_guard(&self) -> RwLockReadGuard<'_, T> {
+        RwLockReadGuard {
+            semaphore: &self.semaphore,
+            value: unsafe { &*self.value.get() },
+        }
+    }
+
+    fn write_guard(&self) -> RwLockWriteGuard<'_, T> {
+        RwLockWriteGuard {
+            semaphore: &self.semaphore,
+            value: unsafe { &mut *self.value.get() },
+        }
+    }
+}
+
+/// Shared access to the value of an `RwLock`, released when this is dropped
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    semaphore: &'a Semaphore,
+    value: &'a T,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+/// Exclusive access to the value of an `RwLock`, released when this is dropped
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    semaphore: &'a Semaphore,
+    value: &'a mut T,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(MAX_READERS);
+    }
+}
+
+/// Readers share the lock, and a waiting writer goes before later readers
+#[test_case]
+fn test_rwlock() {
+    use alloc::boxed::Box;
+    use core::future::Future;
+    use core::task::{Context, Poll};
+    use futures_util::task::noop_waker_ref;
+
+    let lock = RwLock::new(0);
+    let mut context = Context::from_waker(noop_waker_ref());
+    let first = lock.try_read().expect("lock not free");
+    let second = lock.try_read().expect("readers don't share the lock");
+    assert!(lock.try_write().is_none());
+
+    let mut write = Box::pin(lock.write());
+    assert!(write.as_mut().poll(&mut context).is_pending());
+    assert!(lock.try_read().is_none());
+    drop(first);
+    drop(second);
+    match write.as_mut().poll(&mut context) {
+        Poll::Ready(mut guard) => *guard = 1,
+        Poll::Pending => panic!("writer not served"),
+    }
+    drop(write);
+    assert_eq!(*lock.try_read().unwrap(), 1);
+}
@@ -0,0 +1,238 @@
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Error returned when acquiring permits from a closed semaphore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+struct Waiter {
+    id: u64,
+    permits: usize,
+    waker: Waker,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    waiters: VecDeque<Waiter>,
+}
+
+impl State {
+    /// Wake the first waiter if it can go on.
+    fn wake_first(&self) {
+        if let Some(waiter) = self.waiters.front() {
+            if self.closed || waiter.permits <= self.permits {
+                waiter.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// Counting semaphore.
+///
+/// Permits are handed out in the order they were asked for: once a task waits, later requests
+/// wait behind it even if enough permits are available for them.
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+impl Semaphore {
+    /// Create a semaphore with the given number of permits
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                closed: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Number of permits that can be acquired right now
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Add `permits` permits, waking waiting tasks if that is enough for them.
+    pub fn add_permits(&self, permits: usize) {
+        let mut state = self.state.lock();
+        state.permits += permits;
+        state.wake_first();
+    }
+
+    /// Close the semaphore: pending and later acquisitions fail with `AcquireError`.
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        for waiter in &state.waiters {
+            waiter.waker.wake_by_ref();
+        }
+    }
+
+    /// Whether `close` was called
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+
+    /// Wait for a permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Wait for `permits` permits, all taken at once.
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+        }
+    }
+
+    /// Take a permit if one is available and no task is waiting.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Take `permits` permits if they are available and no task is waiting.
+    pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.closed || !state.waiters.is_empty() || state.permits < permits {
+            return None;
+        }
+        state.permits -= permits;
+        Some(SemaphorePermit {
+            semaphore: self,
+            permits,
+        })
+    }
+}
+
+/// Future returned by `Semaphore::acquire` and `Semaphore::acquire_many`
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    /// Set while queued among the waiters
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let this = &mut *self;
+        let mut state = this.semaphore.state.lock();
+        let position = this
+            .id
+            .and_then(|id| state.waiters.iter().position(|waiter| waiter.id == id));
+        if state.closed {
+            if let Some(position) = position {
+                state.waiters.remove(position);
+            }
+            this.id = None;
+            return Poll::Ready(Err(AcquireError));
+        }
+
+        let first = state.waiters.is_empty() || position == Some(0);
+        if first && state.permits >= this.permits {
+            state.permits -= this.permits;
+            if position.is_some() {
+                state.waiters.pop_front();
+            }
+            this.id = None;
+            // permits may be left for the next waiter
+            state.wake_first();
+            return Poll::Ready(Ok(SemaphorePermit {
+                semaphore: this.semaphore,
+                permits: this.permits,
+            }));
+        }
+
+        match position {
+            Some(position) => state.waiters[position].waker = cx.waker().clone(),
+            None => {
+                let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                state.waiters.push_back(Waiter {
+                    id,
+                    permits: this.permits,
+                    waker: cx.waker().clone(),
+                });
+                this.id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.semaphore.state.lock();
+            if let Some(position) = state.waiters.iter().position(|waiter| waiter.id == id) {
+                state.waiters.remove(position);
+                // the next waiter may have been waiting behind this one only
+                state.wake_first();
+            }
+        }
+    }
+}
+
+/// Permits acquired from a `Semaphore`, given back when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Number of permits held
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Keep the permits acquired for good.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+/// Waiters are served in order, and closing fails them
+#[test_case]
+fn test_semaphore() {
+    use futures_util::task::noop_waker_ref;
+    use futures_util::FutureExt;
+
+    let semaphore = Semaphore::new(1);
+    let permit = semaphore.try_acquire().expect("no permit");
+    let mut context = Context::from_waker(noop_waker_ref());
+    let mut first = semaphore.acquire();
+    assert!(Pin::new(&mut first).poll(&mut context).is_pending());
+
+    drop(permit);
+    assert_eq!(semaphore.available_permits(), 1);
+    // the permit is reserved for the waiting task
+    assert!(semaphore.try_acquire().is_none());
+    match Pin::new(&mut first).poll(&mut context) {
+        Poll::Ready(Ok(permit)) => assert_eq!(permit.permits(), 1),
+        _ => panic!("waiter not served"),
+    }
+    drop(first);
+    assert_eq!(semaphore.available_permits(), 1);
+
+    semaphore.close();
+    assert!(matches!(
+        semaphore.acquire().now_or_never(),
+        Some(Err(AcquireError))
+    ));
+}
@@ -0,0 +1,449 @@
+use crate::thread::{self, ThreadId};
+use crate::time::Instant;
+#[cfg(test)]
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+#[cfg(test)]
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use core::task::Waker;
+use core::task::{Context, Poll};
+use core::time::Duration;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Number of slots of the timer wheel
+const SLOTS: usize = 256;
+/// Span of time covered by one slot, in nanoseconds
+const SLOT_NS: u64 = 1_000_000;
+
+/// A pending timer, shared by its future and the wheel
+struct Entry {
+    deadline: Instant,
+    waker: AtomicWaker,
+}
+
+/// Hashed timer wheel: timers are stored in the slot their deadline falls into, modulo the
+/// number of slots. Deadlines further away than a full turn share slots with nearer ones, and
+/// are simply skipped until their turn comes.
+///
+/// Entries are only added and removed by their futures, so the interrupt handler never frees
+/// memory.
+struct Wheel {
+    slots: [Vec<Arc<Entry>>; SLOTS],
+    /// Absolute number of the last slot processed; it is processed again on the next tick, since
+    /// timers may be added to it after it was processed
+    cursor: u64,
+    len: usize,
+}
+
+/// Locked with interrupts disabled outside of the interrupt handler
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    slots: [const { Vec::new() }; SLOTS],
+    cursor: 0,
+    len: 0,
+});
+
+/// Virtual clocks of the `SimpleExecutor`s polling a task, with the thread polling it, see
+/// `VirtualClock::enter`.
+///
+/// Locked with interrupts disabled, so that the thread holding it is never preempted
+static ENTERED_CLOCKS: Mutex<Vec<(Option<ThreadId>, Arc<VirtualClock>)>> = Mutex::new(Vec::new());
+
+fn slot_of(instant: Instant) -> u64 {
+    instant.since_boot().as_nanos() as u64 / SLOT_NS
+}
+
+impl Wheel {
+    fn insert(&mut self, entry: Arc<Entry>) {
+        self.slots[slot_of(entry.deadline) as usize % SLOTS].push(entry);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, entry: &Arc<Entry>) {
+        let slot = &mut self.slots[slot_of(entry.deadline) as usize % SLOTS];
+        if let Some(index) = slot.iter().position(|other| Arc::ptr_eq(other, entry)) {
+            slot.swap_remove(index);
+            self.len -= 1;
+        }
+    }
+
+    /// Wake every timer whose deadline passed, in the slots between the cursor and `now`.
+    fn expire(&mut self, now: Instant) {
+        let current = slot_of(now);
+        if self.len > 0 {
+            // after a full turn every slot has been visited
+            let first = self.cursor.max(current.saturating_sub(SLOTS as u64 - 1));
+            for slot in first..=current {
+                for entry in &self.slots[slot as usize % SLOTS] {
+                    if entry.deadline <= now {
+                        entry.waker.wake();
+                    }
+                }
+            }
+        }
+        self.cursor = current;
+    }
+}
+
+/// Called by the timer interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn expire_timers() {
+    WHEEL.lock().expire(Instant::now());
+}
+
+/// Frozen clock of a `SimpleExecutor` running on virtual time, along with the timers of its
+/// tasks, which are kept out of the wheel.
+///
+/// It only moves forward when the executor calls `advance`, once every task waits.
+pub(super) struct VirtualClock {
+    /// Locked with interrupts disabled
+    now: Mutex<Instant>,
+    /// Locked with interrupts disabled
+    timers: Mutex<Vec<Arc<Entry>>>,
+}
+
+impl VirtualClock {
+    /// Create a clock frozen at the current time.
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(VirtualClock {
+            now: Mutex::new(Instant::now()),
+            timers: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub(super) fn now(&self) -> Instant {
+        without_interrupts(|| *self.now.lock())
+    }
+
+    /// Earliest deadline among the timers of this clock
+    pub(super) fn next_deadline(&self) -> Option<Instant> {
+        without_interrupts(|| {
+            let timers = self.timers.lock();
+            timers.iter().map(|entry| entry.deadline).min()
+        })
+    }
+
+    /// Move the clock forward to `instant` if that is later, waking the timers that expired.
+    pub(super) fn advance(&self, instant: Instant) {
+        without_interrupts(|| {
+            let mut now = self.now.lock();
+            *now = (*now).max(instant);
+            for entry in self.timers.lock().iter() {
+                if entry.deadline <= *now {
+                    entry.waker.wake();
+                }
+            }
+        });
+    }
+
+    /// Run `f`, making timers created by the running thread meanwhile follow this clock.
+    pub(super) fn enter<R>(self: &Arc<Self>, f: impl FnOnce() -> R) -> R {
+        let thread = thread::current_id();
+        without_interrupts(|| ENTERED_CLOCKS.lock().push((thread, self.clone())));
+        let result = f();
+        without_interrupts(|| {
+            let mut entered = ENTERED_CLOCKS.lock();
+            if let Some(index) = entered.iter().rposition(|(other, _)| *other == thread) {
+                entered.remove(index);
+            }
+        });
+        result
+    }
+
+    fn insert(&self, entry: Arc<Entry>) {
+        without_interrupts(|| self.timers.lock().push(entry));
+    }
+
+    fn remove(&self, entry: &Arc<Entry>) {
+        without_interrupts(|| {
+            self.timers
+                .lock()
+                .retain(|other| !Arc::ptr_eq(other, entry))
+        });
+    }
+}
+
+/// Virtual clock entered by the running thread, if any
+fn entered_clock() -> Option<Arc<VirtualClock>> {
+    let thread = thread::current_id();
+    without_interrupts(|| {
+        let entered = ENTERED_CLOCKS.lock();
+        entered
+            .iter()
+            .rev()
+            .find(|(other, _)| *other == thread)
+            .map(|(_, clock)| clock.clone())
+    })
+}
+
+/// Current time as seen by new timers.
+///
+/// This is `Instant::now()`, except while a `SimpleExecutor` running on virtual time polls one of
+/// its tasks: this is then its frozen clock, and the timers created by the task follow it. Other
+/// timers, and the tasks of other executors, keep the real time.
+pub fn now() -> Instant {
+    entered_clock().map_or_else(Instant::now, |clock| clock.now())
+}
+
+/// Earliest deadline among the pending timers, for tickless idle.
+///
+/// Timers following the virtual clock of a `SimpleExecutor` are not included.
+pub fn next_deadline() -> Option<Instant> {
+    without_interrupts(|| {
+        let wheel = WHEEL.lock();
+        wheel
+            .slots
+            .iter()
+            .flatten()
+            .map(|entry| entry.deadline)
+            .min()
+    })
+}
+
+/// Number of timers currently waiting in the wheel
+pub fn pending_timers() -> usize {
+    without_interrupts(|| WHEEL.lock().len)
+}
+
+/// Future completing at a given instant, see `sleep` and `sleep_until`
+pub struct Sleep {
+    deadline: Instant,
+    entry: Option<Arc<Entry>>,
+    /// Virtual clock the sleep was created under, in place of the real time and the wheel
+    clock: Option<Arc<VirtualClock>>,
+}
+
+/// Wait until `duration` has elapsed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(now() + duration)
+}
+
+/// Wait until `deadline` is reached.
+///
+/// Under a `SimpleExecutor` running on virtual time, the deadline is on its clock, see `now`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        entry: None,
+        clock: entered_clock(),
+    }
+}
+
+impl Sleep {
+    /// Instant at which this future completes
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Whether the deadline has been reached
+    pub fn is_elapsed(&self) -> bool {
+        self.now() >= self.deadline
+    }
+
+    /// Current time on the clock this sleep follows
+    fn now(&self) -> Instant {
+        self.clock
+            .as_ref()
+            .map_or_else(Instant::now, |clock| clock.now())
+    }
+
+    /// Change the deadline, as if this future had been created by `sleep_until(deadline)`.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+        self.deadline = deadline;
+    }
+
+    fn cancel(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            match &self.clock {
+                Some(clock) => clock.remove(&entry),
+                None => without_interrupts(|| WHEEL.lock().remove(&entry)),
+            }
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // fast path
+        if self.is_elapsed() {
+            self.cancel();
+            return Poll::Ready(());
+        }
+
+        match &self.entry {
+            Some(entry) => entry.waker.register(cx.waker()),
+            None => {
+                let entry = Arc::new(Entry {
+                    deadline: self.deadline,
+                    waker: AtomicWaker::new(),
+                });
+                entry.waker.register(cx.waker());
+                match &self.clock {
+                    Some(clock) => clock.insert(entry.clone()),
+                    None => without_interrupts(|| WHEEL.lock().insert(entry.clone())),
+                }
+                self.entry = Some(entry);
+            }
+        }
+
+        // the deadline may have passed while registering, after its slot was processed
+        if self.is_elapsed() {
+            self.cancel();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Stream yielding at a fixed period, see `interval`
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+/// Yield every `period`, starting immediately.
+///
+/// When ticks are missed because the task was busy, the next one is scheduled a period after the
+/// late one instead of firing the missed ticks in a burst.
+///
+/// # Panics
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(period > Duration::ZERO, "interval period must be non-zero");
+    Interval {
+        period,
+        sleep: sleep_until(now()),
+    }
+}
+
+impl Interval {
+    /// Wait for the next tick, returning the instant it was scheduled for.
+    pub async fn tick(&mut self) -> Instant {
+        futures_util::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    /// Time between two ticks
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    fn poll_tick(&mut self, cx: &mut Context) -> Poll<Instant> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let scheduled = self.sleep.deadline();
+                let now = self.sleep.now();
+                let mut next = scheduled + self.period;
+                if next <= now {
+                    next = now + self.period;
+                }
+                self.sleep.reset(next);
+                Poll::Ready(scheduled)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+struct Flag(AtomicBool);
+
+#[cfg(test)]
+impl alloc::task::Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Poll `future` until it completes, halting until the timer interrupt wakes it.
+#[cfg(test)]
+pub(super) fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+    loop {
+        flag.0.store(false, Ordering::Relaxed);
+        if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut context) {
+            return output;
+        }
+        while !flag.0.load(Ordering::Relaxed) {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+/// Sleeping waits for the duration and leaves no timer behind
+#[test_case]
+fn test_sleep() {
+    let start = Instant::now();
+    block_on(sleep(Duration::from_millis(3)));
+    assert!(start.elapsed() >= Duration::from_millis(3));
+    assert_eq!(pending_timers(), 0);
+}
+
+/// Sleeps complete off the ticks of the RTC while it drives the timer in place of the PIT
+#[test_case]
+fn test_sleep_on_rtc_ticks() {
+    use crate::time::{self, rtc};
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    let counts = || without_interrupts(|| (time::ticks(), rtc::periodic_ticks()));
+    assert_eq!(rtc::start_timer(1024), Ok(1024));
+    let (ticks, rtc_ticks) = counts();
+    let start = Instant::now();
+    block_on(sleep(Duration::from_millis(5)));
+    let (end_ticks, end_rtc_ticks) = counts();
+    rtc::stop_timer();
+
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    assert!(end_ticks > ticks);
+    // the PIT is masked meanwhile, every tick came from the RTC
+    assert_eq!(end_ticks - ticks, end_rtc_ticks - rtc_ticks);
+    assert!(!rtc::timer_running());
+}
+
+/// Dropping a pending sleep removes its timer
+#[test_case]
+fn test_cancel_sleep() {
+    let waker = Waker::from(Arc::new(Flag(AtomicBool::new(false))));
+    let mut context = Context::from_waker(&waker);
+    let mut sleep = sleep(Duration::from_secs(10));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    assert_eq!(pending_timers(), 1);
+    drop(sleep);
+    assert_eq!(pending_timers(), 0);
+}
+
+/// Intervals tick at their period
+#[test_case]
+fn test_interval() {
+    let mut interval = interval(Duration::from_millis(2));
+    let first = block_on(Box::pin(interval.tick()));
+    let second = block_on(Box::pin(interval.tick()));
+    assert_eq!(second - first, Duration::from_millis(2));
+    assert!(Instant::now() >= second);
+}
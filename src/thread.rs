@@ -0,0 +1,493 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread runs on its own stack, on which its callee-saved registers are pushed while it is
+//! switched out. Scheduling is round-robin: the timer interrupt hands the CPU to the next ready
+//! thread once the running one has used up its time slice. When no thread is ready, an idle
+//! thread halts the CPU until the next interrupt.
+//!
+//! The scheduler is only locked with interrupts disabled, and never across a context switch. The
+//! timer interrupt moves threads between its queues, so these are given enough capacity when a
+//! thread is spawned for the handler to never allocate.
+
+use crate::task::timer;
+use crate::time::{self, Instant};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+/// Size of the stack of every spawned thread
+pub const STACK_SIZE: usize = 32 * 1024;
+/// Timer ticks a thread may run before being preempted
+pub const TIME_SLICE_TICKS: u32 = 10;
+
+/// Unique identifier of a thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The id as a number, the boot thread being 0
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// What a blocked thread waits for
+enum Wait {
+    Until(Instant),
+    Join(ThreadId),
+}
+
+struct Thread {
+    id: ThreadId,
+    /// Stack pointer saved by `switch_context` while the thread is switched out
+    rsp: u64,
+    /// `None` for the boot thread, which keeps the stack it was started on. Only owned, to be
+    /// freed along with the thread.
+    #[allow(dead_code)]
+    stack: Option<Box<[u8]>>,
+    /// Taken by `thread_entry` when the thread first runs
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    wait: Option<Wait>,
+    idle: bool,
+}
+
+impl Thread {
+    /// Create a thread starting in `thread_entry`, which runs `entry`.
+    fn new(entry: Box<dyn FnOnce() + Send>) -> Box<Thread> {
+        let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+        let top = (stack.as_mut_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+        // below a null return address for `thread_entry` comes the one `switch_context` returns
+        // to, then the six callee-saved registers it pops, all zero
+        unsafe { ((top - 16) as *mut u64).write(thread_entry as *const () as u64) };
+        Box::new(Thread {
+            id: ThreadId::new(),
+            rsp: top - 64,
+            stack: Some(stack),
+            entry: Some(entry),
+            wait: None,
+            idle: false,
+        })
+    }
+}
+
+/// Threads are boxed so that their saved stack pointer doesn't move while they are switched out
+#[allow(clippy::vec_box)]
+struct Scheduler {
+    /// `None` until `init` is called
+    current: Option<Box<Thread>>,
+    /// The idle thread, while it is not running
+    idle: Option<Box<Thread>>,
+    ready: VecDeque<Box<Thread>>,
+    blocked: Vec<Box<Thread>>,
+    /// Exited threads, freed by the next thread entering the scheduler
+    zombies: Vec<Box<Thread>>,
+    /// Threads alive, including the boot and idle threads
+    count: usize,
+    /// Ticks left before the current thread is preempted
+    slice_left: u32,
+}
+
+impl Scheduler {
+    /// Make every blocked thread whose wait is over ready again.
+    fn wake<F: Fn(&Wait) -> bool>(&mut self, done: F) {
+        let mut index = 0;
+        while index < self.blocked.len() {
+            if self.blocked[index].wait.as_ref().is_some_and(&done) {
+                let mut thread = self.blocked.swap_remove(index);
+                thread.wait = None;
+                self.ready.push_back(thread);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+/// Locked with interrupts disabled only
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    current: None,
+    idle: None,
+    ready: VecDeque::new(),
+    blocked: Vec::new(),
+    zombies: Vec::new(),
+    count: 0,
+    slice_left: 0,
+});
+
+/// Set by the timer interrupt when the current thread should be preempted
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// Turn the running code into the boot thread and create the idle thread.
+///
+/// Must be called once the heap is initialized.
+pub fn init() {
+    let boot = Box::new(Thread {
+        id: ThreadId::new(),
+        rsp: 0,
+        stack: None,
+        entry: None,
+        wait: None,
+        idle: false,
+    });
+    let mut idle = Thread::new(Box::new(|| idle_loop()));
+    idle.idle = true;
+
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        assert!(scheduler.current.is_none(), "thread::init called twice");
+        scheduler.current = Some(boot);
+        scheduler.idle = Some(idle);
+        scheduler.count = 2;
+        scheduler.ready.reserve(2);
+        scheduler.blocked.reserve(2);
+        scheduler.slice_left = TIME_SLICE_TICKS;
+    });
+}
+
+/// Handle to a spawned thread, to wait for its result
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    /// Written by the thread when its closure returns, with interrupts disabled
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Id of the thread
+    pub fn thread_id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Whether the thread's closure has returned
+    pub fn is_finished(&self) -> bool {
+        without_interrupts(|| self.result.lock().is_some())
+    }
+
+    /// Block until the thread finishes, returning what its closure returned.
+    ///
+    /// # Panics
+    /// Panics if called from the thread itself.
+    pub fn join(self) -> T {
+        assert_ne!(current_id(), Some(self.id), "thread joining itself");
+        loop {
+            let result = without_interrupts(|| {
+                let scheduler = SCHEDULER.lock();
+                let result = self.result.lock().take();
+                if result.is_none() {
+                    reschedule(scheduler, Switch::Block(Wait::Join(self.id)));
+                }
+                result
+            });
+            if let Some(result) = result {
+                reap();
+                return result;
+            }
+        }
+    }
+}
+
+/// Start a new thread running `f`.
+///
+/// # Panics
+/// Panics if called before `init`.
+pub fn spawn_thread<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    reap();
+    let result = Arc::new(Mutex::new(None));
+    let packet = result.clone();
+    let thread = Thread::new(Box::new(move || {
+        let value = f();
+        without_interrupts(|| *packet.lock() = Some(value));
+    }));
+    let id = thread.id;
+
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        assert!(
+            scheduler.current.is_some(),
+            "thread spawned before thread::init"
+        );
+        // every thread may end up in either queue from the timer interrupt
+        scheduler.count += 1;
+        let count = scheduler.count;
+        scheduler.ready.reserve(count);
+        scheduler.blocked.reserve(count);
+        scheduler.ready.push_back(thread);
+    });
+    JoinHandle { id, result }
+}
+
+/// Let the next ready thread run, if any.
+pub fn yield_now() {
+    reap();
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        if scheduler.current.is_some() {
+            reschedule(scheduler, Switch::Yield);
+        }
+    });
+}
+
+/// Block the current thread for at least `duration`.
+///
+/// Before `init`, this busy-waits instead.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    if current_id().is_none() {
+        time::busy_wait(duration);
+        return;
+    }
+    while Instant::now() < deadline {
+        without_interrupts(|| {
+            reschedule(SCHEDULER.lock(), Switch::Block(Wait::Until(deadline)));
+        });
+    }
+}
+
+/// Id of the running thread, or `None` before `init`
+pub fn current_id() -> Option<ThreadId> {
+    without_interrupts(|| SCHEDULER.lock().current.as_ref().map(|thread| thread.id))
+}
+
+/// Number of threads alive, including the boot and idle threads
+pub fn thread_count() -> usize {
+    without_interrupts(|| SCHEDULER.lock().count)
+}
+
+/// Number of threads waiting for the CPU, besides the running one
+pub fn ready_count() -> usize {
+    without_interrupts(|| SCHEDULER.lock().ready.len())
+}
+
+/// Earliest instant at which a sleeping thread wakes up, for tickless idle
+pub fn next_wakeup() -> Option<Instant> {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .blocked
+            .iter()
+            .filter_map(|thread| match thread.wait {
+                Some(Wait::Until(deadline)) => Some(deadline),
+                _ => None,
+            })
+            .min()
+    })
+}
+
+/// Wake the threads whose sleep is over and account for the time slice. Called by the timer
+/// interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn tick() {
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.current.is_none() {
+        return;
+    }
+    let now = Instant::now();
+    scheduler.wake(|wait| matches!(wait, Wait::Until(deadline) if *deadline <= now));
+    scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+    if scheduler.slice_left == 0 && !scheduler.ready.is_empty() {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switch to the next thread if the timer interrupt asked for it. Called at the very end of
+/// interrupt handling, once the interrupt was acknowledged.
+///
+/// A CPU halted in `time::idle` is not preempted: the idle loops look for ready threads as soon
+/// as the halt ends, and preempting them would count the time other threads run as idle.
+pub(crate) fn preempt_if_needed() {
+    if !NEED_RESCHED.swap(false, Ordering::Relaxed) || time::is_halted() {
+        return;
+    }
+    let scheduler = SCHEDULER.lock();
+    if scheduler
+        .current
+        .as_ref()
+        .is_some_and(|thread| !thread.idle)
+    {
+        reschedule(scheduler, Switch::Yield);
+    }
+}
+
+/// Where the running thread goes when switched out
+enum Switch {
+    /// Back at the end of the ready queue
+    Yield,
+    /// Among the blocked threads, until the wait is over
+    Block(Wait),
+    /// Among the zombies, never to run again
+    Exit,
+}
+
+/// Switch to the next ready thread, or to the idle thread if there is none.
+///
+/// Must be called with interrupts disabled. Returns once the calling thread runs again, right away
+/// when yielding with no other thread ready.
+fn reschedule(mut scheduler: MutexGuard<Scheduler>, switch: Switch) {
+    let next = match scheduler.ready.pop_front() {
+        Some(next) => next,
+        None if matches!(switch, Switch::Yield) => return,
+        None => scheduler.idle.take().expect("idle thread blocked"),
+    };
+    let mut previous = scheduler
+        .current
+        .replace(next)
+        .expect("scheduler not initialized");
+    // the thread is boxed, so this stays valid when the box moves to a queue
+    let previous_rsp: *mut u64 = &mut previous.rsp;
+    let next_rsp = scheduler.current.as_ref().map_or(0, |thread| thread.rsp);
+    scheduler.slice_left = TIME_SLICE_TICKS;
+
+    match switch {
+        Switch::Yield if previous.idle => scheduler.idle = Some(previous),
+        Switch::Yield => scheduler.ready.push_back(previous),
+        Switch::Block(wait) => {
+            previous.wait = Some(wait);
+            scheduler.blocked.push(previous);
+        }
+        Switch::Exit => {
+            let id = previous.id;
+            scheduler.wake(|wait| matches!(wait, Wait::Join(joined) if *joined == id));
+            scheduler.count -= 1;
+            scheduler.zombies.push(previous);
+        }
+    }
+
+    drop(scheduler);
+    unsafe { switch_context(previous_rsp, next_rsp) };
+}
+
+/// Free the stacks of exited threads.
+fn reap() {
+    let zombies = without_interrupts(|| mem::take(&mut SCHEDULER.lock().zombies));
+    drop(zombies);
+}
+
+/// Save the callee-saved registers on the current stack and its pointer in `*previous_rsp`, then
+/// restore those of the thread whose stack pointer is `next_rsp` and return into it.
+///
+/// Everything else is saved by the caller, as for any other call. Interrupts stay disabled across
+/// the switch.
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(previous_rsp: *mut u64, next_rsp: u64) {
+    core::arch::naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    );
+}
+
+/// First code run by every spawned thread, returned into by `switch_context`.
+extern "C" fn thread_entry() -> ! {
+    let entry = SCHEDULER
+        .lock()
+        .current
+        .as_mut()
+        .and_then(|thread| thread.entry.take())
+        .expect("thread started twice");
+    interrupts::enable();
+    entry();
+    exit();
+}
+
+/// End the current thread, waking the threads joining it.
+fn exit() -> ! {
+    interrupts::disable();
+    reschedule(SCHEDULER.lock(), Switch::Exit);
+    unreachable!("exited thread scheduled again");
+}
+
+/// Run by the idle thread: halt until some thread is ready.
+fn idle_loop() -> ! {
+    loop {
+        interrupts::disable();
+        if ready_count() > 0 {
+            interrupts::enable();
+            yield_now();
+        } else {
+            time::idle(
+                timer::next_deadline()
+                    .into_iter()
+                    .chain(next_wakeup())
+                    .min(),
+            );
+        }
+    }
+}
+
+/// Joining returns the value computed by the thread
+#[test_case]
+fn test_join() {
+    let handle = spawn_thread(|| 6 * 7);
+    assert_ne!(Some(handle.thread_id()), current_id());
+    assert_eq!(handle.join(), 42);
+}
+
+/// A thread that never yields doesn't keep others from running
+#[test_case]
+fn test_preemption() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static SPINS: AtomicU64 = AtomicU64::new(0);
+
+    let spinner = spawn_thread(|| {
+        while !STOP.load(Ordering::Relaxed) {
+            SPINS.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    // the boot thread doesn't yield either
+    time::busy_wait(Duration::from_millis(50));
+    assert!(SPINS.load(Ordering::Relaxed) > 0);
+    STOP.store(true, Ordering::Relaxed);
+    spinner.join();
+}
+
+/// Sleeping threads wake up after their deadline
+#[test_case]
+fn test_sleep() {
+    let handle = spawn_thread(|| {
+        let start = Instant::now();
+        sleep(Duration::from_millis(5));
+        start.elapsed()
+    });
+    assert!(handle.join() >= Duration::from_millis(5));
+}
+
+/// Yielding runs the other ready threads
+#[test_case]
+fn test_yield_now() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    let count = thread_count();
+    let handle = spawn_thread(|| RAN.store(true, Ordering::Relaxed));
+    yield_now();
+    assert!(RAN.load(Ordering::Relaxed));
+    handle.join();
+    assert_eq!(thread_count(), count);
+}
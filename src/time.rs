@@ -0,0 +1,356 @@
+//! Monotonic time since boot.
+//!
+//! The timer interrupt counts ticks and advances a coarse clock. Better clock sources take over
+//! when available: the HPET main counter once `hpet::init` succeeded, and above all an invariant
+//! time stamp counter, which is calibrated at boot and gives nanosecond resolution.
+
+use crate::percpu;
+use core::convert::TryFrom;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+pub use rtc::DateTime;
+
+/// High precision event timer
+pub mod hpet;
+/// Programmable interval timer
+pub mod pit;
+/// CMOS real-time clock
+pub mod rtc;
+/// Time stamp counter
+pub mod tsc;
+
+/// Frequency the timer interrupt is programmed to at boot, in Hz
+pub const TIMER_FREQUENCY: u32 = 1000;
+
+/// Timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds since boot, advanced by the period of the timer on every tick.
+///
+/// Accumulating the period instead of multiplying the tick count keeps the clock monotonic and
+/// correct when the timer frequency changes.
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Period of the timer in nanoseconds, cached so the interrupt handler doesn't recompute it
+static PERIOD_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Unix time in nanoseconds at which the monotonic clock started
+static BOOT_UNIX_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the periodic timer is stopped while idle, see `set_tickless`
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+
+/// Set while `idle` has replaced the periodic timer by a one-shot countdown
+static ONE_SHOT: AtomicBool = AtomicBool::new(false);
+
+/// Set while the CPU is halted in `idle`
+static HALTED: AtomicBool = AtomicBool::new(false);
+
+/// Program the timer to `TIMER_FREQUENCY`, calibrate the TSC and read the date from the RTC.
+pub fn init() {
+    set_frequency(TIMER_FREQUENCY);
+    tsc::calibrate();
+    let boot = rtc::read().unix_nanos().saturating_sub(now_ns());
+    BOOT_UNIX_NS.store(boot, Ordering::Relaxed);
+}
+
+/// Change the frequency of the PIT, returning the frequency actually obtained.
+///
+/// This only affects the timer interrupt while neither the HPET nor the RTC drives it.
+pub fn set_frequency(hz: u32) -> u32 {
+    let actual = pit::set_frequency(hz);
+    if !hpet::timer_running() && !rtc::timer_running() {
+        set_tick_period(pit::period_ns());
+    }
+    actual
+}
+
+/// Set the time between two timer interrupts, when their source changes.
+fn set_tick_period(ns: u64) {
+    PERIOD_NS.store(ns, Ordering::Relaxed);
+}
+
+/// Advance the clock by one timer period. Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    let period = match PERIOD_NS.load(Ordering::Relaxed) {
+        // the PIT runs at its power-on rate until `init` is called
+        0 => pit::period_ns(),
+        period => period,
+    };
+    UPTIME_NS.fetch_add(period, Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Release);
+
+    // the handler may switch to another thread before `idle` returns
+    if ONE_SHOT.swap(false, Ordering::Relaxed) {
+        pit::restart_periodic();
+        set_tick_period(pit::period_ns());
+    }
+}
+
+/// Number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// Nanoseconds elapsed since boot
+pub fn now_ns() -> u64 {
+    tsc::now_ns()
+        .or_else(hpet::now_ns)
+        .unwrap_or_else(|| UPTIME_NS.load(Ordering::Relaxed))
+}
+
+/// Time elapsed since boot
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now_ns())
+}
+
+/// Enable or disable tickless idle, returning whether it is now enabled.
+///
+/// In tickless mode, `idle` replaces the periodic timer interrupt by a single one at the next
+/// deadline. Ticks are then no longer regular, so this needs a clock that runs on its own (the
+/// TSC or the HPET), and the PIT to drive the timer interrupt.
+pub fn set_tickless(enabled: bool) -> bool {
+    let supported = (tsc::frequency().is_some() || hpet::is_enabled())
+        && !hpet::timer_running()
+        && !rtc::timer_running();
+    let enabled = enabled && supported;
+    TICKLESS.store(enabled, Ordering::Relaxed);
+    enabled
+}
+
+/// Whether tickless idle is enabled
+pub fn is_tickless() -> bool {
+    TICKLESS.load(Ordering::Relaxed)
+}
+
+/// Halt the CPU until the next interrupt, then return with interrupts enabled.
+///
+/// Must be called with interrupts disabled, after checking there is nothing left to do, so that
+/// no wakeup is missed between the check and the halt. In tickless mode, the only timer interrupt
+/// scheduled is the one at `next_deadline`, or none at all without a deadline. The time spent
+/// halted is added to the idle statistics of the CPU.
+pub fn idle(next_deadline: Option<Instant>) {
+    use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+    let start = Instant::now();
+    if is_tickless() {
+        match next_deadline {
+            Some(deadline) => {
+                let delay = deadline.checked_duration_since(start).unwrap_or_default();
+                set_tick_period(pit::set_one_shot(delay.as_nanos() as u64));
+            }
+            None => {
+                // the countdown runs out once, then the PIT stays silent
+                set_tick_period(pit::set_one_shot(pit::MAX_ONE_SHOT_NS));
+            }
+        }
+        ONE_SHOT.store(true, Ordering::Relaxed);
+    }
+
+    HALTED.store(true, Ordering::Relaxed);
+    enable_and_hlt();
+    HALTED.store(false, Ordering::Relaxed);
+
+    // woken by another interrupt than the timer
+    interrupts::without_interrupts(|| {
+        if ONE_SHOT.swap(false, Ordering::Relaxed) {
+            pit::restart_periodic();
+            set_tick_period(pit::period_ns());
+        }
+    });
+    percpu::current().record_idle(start.elapsed());
+}
+
+/// Whether the CPU is halted in `idle`, for interrupt handlers
+pub(crate) fn is_halted() -> bool {
+    HALTED.load(Ordering::Relaxed)
+}
+
+/// Current date and time in UTC.
+///
+/// It is derived from the monotonic clock and the RTC reading at boot, so it is only accurate to
+/// the second, but never goes backwards.
+pub fn wall_clock() -> DateTime {
+    DateTime::from_unix_nanos(BOOT_UNIX_NS.load(Ordering::Relaxed) + now_ns())
+}
+
+/// Smallest step by which the clock advances
+pub fn resolution() -> Duration {
+    if tsc::frequency().is_some() {
+        Duration::from_nanos(1)
+    } else if hpet::is_enabled() {
+        Duration::from_nanos(hpet::ticks_to_ns(1).max(1))
+    } else {
+        Duration::from_nanos(PERIOD_NS.load(Ordering::Relaxed))
+    }
+}
+
+/// Spin until `duration` has elapsed.
+///
+/// Without a calibrated TSC the clock only advances in the timer interrupt, so this must not be
+/// called with interrupts disabled in that case.
+pub fn busy_wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+}
+
+/// Run `f`, returning its result and how long it took.
+pub fn measure<T, F: FnOnce() -> T>(f: F) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+/// Point in time measured by the monotonic clock, as nanoseconds since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current time
+    pub fn now() -> Self {
+        Instant(now_ns())
+    }
+
+    /// Time elapsed since this instant
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Time elapsed from `earlier` to this instant, or zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// Time elapsed from `earlier` to this instant, or `None` if `earlier` is later
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// This instant moved `duration` forward, or `None` on overflow
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+
+    /// This instant moved `duration` backward, or `None` if that is before boot
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Instant)
+    }
+
+    /// Time since boot at this instant
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Instant arithmetic saturates or checks instead of wrapping
+#[test_case]
+fn test_instant_arithmetic() {
+    let start = Instant(1_000);
+    let later = start + Duration::from_nanos(500);
+    assert_eq!(later, Instant(1_500));
+    assert_eq!(later - start, Duration::from_nanos(500));
+    assert_eq!(start - later, Duration::ZERO);
+    assert_eq!(start.checked_duration_since(later), None);
+    assert_eq!(start.checked_sub(Duration::from_nanos(1_001)), None);
+    assert_eq!(Instant(u64::MAX).checked_add(Duration::from_nanos(1)), None);
+    assert_eq!(later.since_boot(), Duration::from_nanos(1_500));
+}
+
+/// The timer interrupt keeps the clock running
+#[test_case]
+fn test_uptime_advances() {
+    let start = Instant::now();
+    let ticks = ticks();
+    while self::ticks() < ticks + 2 {
+        x86_64::instructions::hlt();
+    }
+    assert!(start.elapsed() >= Duration::from_nanos(pit::period_ns()));
+    assert!(uptime() >= start.since_boot());
+}
+
+/// Busy waits last at least as long as requested
+#[test_case]
+fn test_busy_wait() {
+    let ((), elapsed) = measure(|| busy_wait(Duration::from_millis(2)));
+    assert!(elapsed >= Duration::from_millis(2));
+}
+
+/// The wall clock follows the RTC
+#[test_case]
+fn test_wall_clock() {
+    let rtc = rtc::read();
+    let wall_clock = wall_clock();
+    assert!(wall_clock.year >= 2020);
+    let difference = wall_clock.unix_nanos() as i64 - rtc.unix_nanos() as i64;
+    assert!(difference.abs() < 2_000_000_000, "off by {}ns", difference);
+}
+
+/// In tickless mode, an idle CPU is only woken by the deadline
+#[test_case]
+fn test_tickless_idle() {
+    use x86_64::instructions::interrupts;
+
+    if !set_tickless(true) {
+        crate::serial_print!("(no free running clock) ");
+        return;
+    }
+    let cpu = percpu::current();
+    let halts = cpu.halts();
+    let idle_time = cpu.idle_time();
+    let ticks = ticks();
+    let deadline = Instant::now() + Duration::from_millis(20);
+    while Instant::now() < deadline {
+        interrupts::disable();
+        idle(Some(deadline));
+    }
+    set_tickless(false);
+
+    // a periodic tick would have fired about 20 times
+    assert!(self::ticks() - ticks < 10);
+    assert!(cpu.halts() > halts);
+    assert!(cpu.idle_time() > idle_time);
+    assert!(cpu.busy_time() < uptime());
+}
@@ -0,0 +1,297 @@
+//! Driver for the high precision event timer.
+//!
+//! The main counter serves as a clock source, and timer 0 can raise one-shot or periodic
+//! interrupts. With the legacy 8259 PICs, the only way to route those is the legacy replacement
+//! mode: timer 0 then takes over IRQ 0 from the PIT and its interrupts go through the same handler.
+//! Legacy replacement also claims IRQ 8 for timer 1, so the RTC interrupt is not delivered while
+//! timer 0 is running.
+
+use super::pit;
+use core::convert::TryFrom;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Physical address of the HPET registers on PC compatible machines, including QEMU
+pub const DEFAULT_BASE: u64 = 0xFED0_0000;
+/// Virtual address the registers are mapped at
+pub const REGISTERS_START: u64 = 0x4444_5555_0000;
+
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+const TIMER_0_CONFIGURATION: u64 = 0x100;
+const TIMER_0_COMPARATOR: u64 = 0x108;
+
+const CAPABILITIES_64_BIT: u64 = 1 << 13;
+const CAPABILITIES_LEGACY_ROUTE: u64 = 1 << 15;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_SET_ACCUMULATOR: u64 = 1 << 6;
+const TIMER_32_BIT: u64 = 1 << 8;
+
+/// The specification caps the counter period at 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+
+/// Set once the registers are mapped and the counter is running
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Femtoseconds per counter increment
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// Counter value and clock reading when the HPET was enabled
+static BASE_COUNTER: AtomicU64 = AtomicU64::new(0);
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Serializes updates of the configuration registers
+static CONFIGURATION_LOCK: Mutex<()> = Mutex::new(());
+
+/// Reasons why the HPET can't be used
+#[derive(Debug)]
+pub enum InitError {
+    /// Mapping the registers failed
+    Map(MapToError<Size4KiB>),
+    /// Nothing looking like an HPET answers at the base address
+    NotPresent,
+    /// The counter is only 32 bits wide, it would wrap within minutes
+    Counter32Bit,
+}
+
+/// How timer 0 fires
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// A single interrupt after the given delay
+    OneShot(Duration),
+    /// An interrupt every period
+    Periodic(Duration),
+}
+
+/// Reasons why timer 0 can't be started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// `init` did not succeed
+    NotEnabled,
+    /// The HPET can't take over IRQ 0
+    NoLegacyRoute,
+    /// Timer 0 has no periodic mode
+    NotPeriodic,
+    /// The delay or period doesn't fit the comparator, or is zero
+    InvalidDuration,
+}
+
+fn read(offset: u64) -> u64 {
+    unsafe { read_volatile((REGISTERS_START + offset) as *const u64) }
+}
+
+fn write(offset: u64, value: u64) {
+    unsafe { write_volatile((REGISTERS_START + offset) as *mut u64, value) }
+}
+
+/// Map the HPET registers at `REGISTERS_START`, check them, and start the main counter.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), InitError> {
+    let page = Page::containing_address(VirtAddr::new(REGISTERS_START));
+    let frame = PhysFrame::containing_address(PhysAddr::new(DEFAULT_BASE));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, frame_allocator)
+            .map_err(InitError::Map)?
+            .flush();
+    }
+
+    let capabilities = read(CAPABILITIES);
+    let period = capabilities >> 32;
+    if capabilities == u64::MAX || period == 0 || period > MAX_PERIOD_FS {
+        return Err(InitError::NotPresent);
+    }
+    if capabilities & CAPABILITIES_64_BIT == 0 {
+        return Err(InitError::Counter32Bit);
+    }
+
+    without_interrupts(|| {
+        let _lock = CONFIGURATION_LOCK.lock();
+        write(CONFIGURATION, read(CONFIGURATION) | CONFIGURATION_ENABLE);
+        PERIOD_FS.store(period, Ordering::Relaxed);
+        BASE_NS.store(super::now_ns(), Ordering::Relaxed);
+        BASE_COUNTER.store(read(MAIN_COUNTER), Ordering::Relaxed);
+        ENABLED.store(true, Ordering::Release);
+    });
+
+    // a better reference than the PIT for the TSC
+    super::tsc::calibrate();
+    Ok(())
+}
+
+/// Whether `init` succeeded
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Current value of the main counter, or `None` if the HPET is not enabled
+pub fn counter() -> Option<u64> {
+    if is_enabled() {
+        Some(read(MAIN_COUNTER))
+    } else {
+        None
+    }
+}
+
+/// Counter increments per second, or `None` if the HPET is not enabled
+pub fn frequency() -> Option<u64> {
+    if is_enabled() {
+        Some(1_000_000_000_000_000 / PERIOD_FS.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// Nanoseconds represented by `ticks` counter increments
+pub(super) fn ticks_to_ns(ticks: u64) -> u64 {
+    (u128::from(ticks) * u128::from(PERIOD_FS.load(Ordering::Relaxed)) / u128::from(FS_PER_NS))
+        as u64
+}
+
+fn ns_to_ticks(ns: u128) -> Option<u64> {
+    let ticks = ns * u128::from(FS_PER_NS) / u128::from(PERIOD_FS.load(Ordering::Relaxed));
+    u64::try_from(ticks).ok().filter(|&ticks| ticks > 0)
+}
+
+/// Clock reading in nanoseconds, or `None` if the HPET is not enabled
+pub(super) fn now_ns() -> Option<u64> {
+    let ticks = counter()?.wrapping_sub(BASE_COUNTER.load(Ordering::Relaxed));
+    Some(BASE_NS.load(Ordering::Relaxed) + ticks_to_ns(ticks))
+}
+
+/// Start timer 0, routed to IRQ 0 in place of the PIT.
+///
+/// Every interrupt goes through the regular timer handler and advances the tick count by the
+/// period (or the delay, for a one-shot timer). IRQ 0 stays with the HPET until `stop_timer`,
+/// so no further ticks arrive after a one-shot interrupt.
+pub fn start_timer(mode: TimerMode) -> Result<(), TimerError> {
+    if !is_enabled() {
+        return Err(TimerError::NotEnabled);
+    }
+    if read(CAPABILITIES) & CAPABILITIES_LEGACY_ROUTE == 0 {
+        return Err(TimerError::NoLegacyRoute);
+    }
+    let (duration, periodic) = match mode {
+        TimerMode::OneShot(delay) => (delay, false),
+        TimerMode::Periodic(period) => (period, true),
+    };
+    let ticks = ns_to_ticks(duration.as_nanos()).ok_or(TimerError::InvalidDuration)?;
+
+    let timer = read(TIMER_0_CONFIGURATION);
+    if periodic && timer & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err(TimerError::NotPeriodic);
+    }
+
+    without_interrupts(|| {
+        let _lock = CONFIGURATION_LOCK.lock();
+        // edge triggered, 64 bit comparator
+        let mut timer = timer
+            & !(TIMER_LEVEL_TRIGGERED | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_32_BIT);
+        if periodic {
+            timer |= TIMER_PERIODIC | TIMER_SET_ACCUMULATOR;
+        }
+        write(TIMER_0_CONFIGURATION, timer | TIMER_INTERRUPT_ENABLE);
+
+        // in periodic mode, the first write sets the next deadline and the second the period
+        let deadline = read(MAIN_COUNTER).wrapping_add(ticks);
+        write(TIMER_0_COMPARATOR, deadline);
+        if periodic {
+            write(TIMER_0_COMPARATOR, ticks);
+        }
+
+        super::set_tick_period(ticks_to_ns(ticks));
+        write(
+            CONFIGURATION,
+            read(CONFIGURATION) | CONFIGURATION_LEGACY_ROUTE,
+        );
+    });
+    Ok(())
+}
+
+/// Stop timer 0 and give IRQ 0 back to the PIT.
+pub fn stop_timer() {
+    if !is_enabled() {
+        return;
+    }
+    without_interrupts(|| {
+        let _lock = CONFIGURATION_LOCK.lock();
+        write(
+            CONFIGURATION,
+            read(CONFIGURATION) & !CONFIGURATION_LEGACY_ROUTE,
+        );
+        write(
+            TIMER_0_CONFIGURATION,
+            read(TIMER_0_CONFIGURATION) & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+        );
+        super::set_tick_period(pit::period_ns());
+    });
+}
+
+/// Whether timer 0 currently drives the timer interrupt
+pub fn timer_running() -> bool {
+    is_enabled() && read(CONFIGURATION) & CONFIGURATION_LEGACY_ROUTE != 0
+}
+
+/// The counter advances at the advertised rate
+#[test_case]
+fn test_counter() {
+    let start = match counter() {
+        Some(start) => start,
+        None => {
+            crate::serial_print!("(no HPET) ");
+            return;
+        }
+    };
+    assert!(frequency().unwrap() > 0);
+    let ticks = ns_to_ticks(1_000_000).unwrap();
+    while counter().unwrap().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+    assert!(now_ns().unwrap() >= BASE_NS.load(Ordering::Relaxed) + 1_000_000);
+}
+
+/// A periodic timer 0 keeps the timer interrupt going, and the PIT takes over again afterwards
+#[test_case]
+fn test_periodic_timer() {
+    if !is_enabled() {
+        crate::serial_print!("(no HPET) ");
+        return;
+    }
+    start_timer(TimerMode::Periodic(Duration::from_millis(1))).expect("failed to start timer");
+    assert!(timer_running());
+    let ticks = super::ticks();
+    while super::ticks() < ticks + 3 {
+        x86_64::instructions::hlt();
+    }
+
+    stop_timer();
+    assert!(!timer_running());
+    let ticks = super::ticks();
+    while super::ticks() < ticks + 2 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(
+        start_timer(TimerMode::OneShot(Duration::ZERO)),
+        Err(TimerError::InvalidDuration)
+    );
+}
@@ -0,0 +1,163 @@
+//! Driver for the 8253/8254 programmable interval timer.
+//!
+//! Channel 0 is wired to IRQ 0 and drives the timer interrupt. Channel 2 is only used to busy-wait
+//! for a precise number of oscillator cycles, to calibrate other clocks.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+/// Frequency of the oscillator driving the PIT, in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+/// IRQ line of channel 0
+pub const IRQ: u8 = 0;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B, which gates channel 2 and reports its output
+const PORT_B: u16 = 0x61;
+
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary counting
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Channel 0, low byte then high byte, mode 0 (interrupt on terminal count), binary counting
+const CHANNEL_0_ONE_SHOT: u8 = 0b0011_0000;
+/// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary counting
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+const PORT_B_GATE_2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT_2: u8 = 1 << 5;
+
+struct Pit {
+    channel_0: Port<u8>,
+    channel_2: Port<u8>,
+    command: Port<u8>,
+    port_b: Port<u8>,
+}
+
+static PIT: Mutex<Pit> = Mutex::new(Pit {
+    channel_0: Port::new(CHANNEL_0),
+    channel_2: Port::new(CHANNEL_2),
+    command: Port::new(COMMAND),
+    port_b: Port::new(PORT_B),
+});
+
+/// Reload value currently programmed into channel 0; 65536 is the power-on default
+static DIVISOR: AtomicU32 = AtomicU32::new(65_536);
+
+/// Longest delay a one-shot countdown can reach, in nanoseconds
+pub const MAX_ONE_SHOT_NS: u64 = 65_536 * 1_000_000_000 / BASE_FREQUENCY as u64;
+
+/// Reload value giving the frequency closest to `hz`, clamped to what the counter can hold.
+fn divisor_for(hz: u32) -> u32 {
+    let hz = hz.max(1);
+    ((BASE_FREQUENCY + hz / 2) / hz).clamp(1, 65_536)
+}
+
+/// Program channel 0 to fire at (approximately) `hz` times per second.
+///
+/// Returns the frequency actually obtained, since it is limited to integer divisors of
+/// `BASE_FREQUENCY`.
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = divisor_for(hz);
+    without_interrupts(|| {
+        program_channel_0(CHANNEL_0_RATE_GENERATOR, divisor);
+        DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+
+    frequency()
+}
+
+/// Switch channel 0 to a single interrupt after (approximately) `ns` nanoseconds, for tickless
+/// idle. It then stays silent until `restart_periodic` is called.
+///
+/// The delay is clamped to `MAX_ONE_SHOT_NS`. Returns the delay actually programmed.
+pub fn set_one_shot(ns: u64) -> u64 {
+    let count = (u128::from(ns) * u128::from(BASE_FREQUENCY) / 1_000_000_000).clamp(1, 65_536);
+    program_channel_0(CHANNEL_0_ONE_SHOT, count as u32);
+    count as u64 * 1_000_000_000 / u64::from(BASE_FREQUENCY)
+}
+
+/// Go back to periodic interrupts at the frequency last set by `set_frequency`.
+pub fn restart_periodic() {
+    program_channel_0(CHANNEL_0_RATE_GENERATOR, divisor());
+}
+
+fn program_channel_0(command: u8, count: u32) {
+    // a count of 0 stands for 65536
+    let [low, high, ..] = (count as u16).to_le_bytes();
+    without_interrupts(|| {
+        let mut pit = PIT.lock();
+        unsafe {
+            pit.command.write(command);
+            pit.channel_0.write(low);
+            pit.channel_0.write(high);
+        }
+    });
+}
+
+/// Reload value currently programmed into channel 0
+pub fn divisor() -> u32 {
+    DIVISOR.load(Ordering::Relaxed)
+}
+
+/// Current interrupt frequency of channel 0, rounded to the nearest Hz
+pub fn frequency() -> u32 {
+    let divisor = divisor();
+    (BASE_FREQUENCY + divisor / 2) / divisor
+}
+
+/// Time between two interrupts of channel 0, in nanoseconds
+pub fn period_ns() -> u64 {
+    (u64::from(divisor()) * 1_000_000_000 + u64::from(BASE_FREQUENCY) / 2)
+        / u64::from(BASE_FREQUENCY)
+}
+
+/// Busy-wait until channel 2 has counted `cycles` cycles of the oscillator.
+///
+/// `start` is called right after the countdown begins and `stop` right after it ends, so the
+/// wait can be measured against another clock. Interrupts are disabled meanwhile, to keep
+/// handlers from stretching the measurement.
+pub fn wait_cycles<S, E>(cycles: u16, start: S, stop: E)
+where
+    S: FnOnce(),
+    E: FnOnce(),
+{
+    without_interrupts(|| {
+        let mut pit = PIT.lock();
+        unsafe {
+            // keep the gate low while loading the count, and the speaker off
+            let port_b = pit.port_b.read() & !(PORT_B_GATE_2 | PORT_B_SPEAKER);
+            pit.port_b.write(port_b);
+
+            let [low, high] = cycles.to_le_bytes();
+            pit.command.write(CHANNEL_2_ONE_SHOT);
+            pit.channel_2.write(low);
+            pit.channel_2.write(high);
+
+            // a rising edge on the gate starts the countdown
+            pit.port_b.write(port_b | PORT_B_GATE_2);
+            start();
+            while pit.port_b.read() & PORT_B_OUT_2 == 0 {
+                core::hint::spin_loop();
+            }
+            stop();
+
+            pit.port_b.write(port_b);
+        }
+    });
+}
+
+/// Divisors are rounded to the closest frequency and clamped to the 16 bit counter
+#[test_case]
+fn test_divisor_for() {
+    assert_eq!(divisor_for(1000), 1193);
+    assert_eq!(divisor_for(100), 11932);
+    assert_eq!(divisor_for(BASE_FREQUENCY), 1);
+    assert_eq!(divisor_for(u32::MAX), 1);
+    assert_eq!(divisor_for(1), 65_536);
+    assert_eq!(divisor_for(0), 65_536);
+    assert_eq!(divisor_for(18), 65_536);
+}
@@ -0,0 +1,416 @@
+//! Driver for the CMOS real-time clock.
+//!
+//! The RTC gives the date and time with a one second resolution, and can raise a periodic
+//! interrupt on IRQ 8, which can also drive the timer in place of the PIT. Note that the HPET's
+//! legacy replacement mode takes IRQ 8 over.
+
+use crate::interrupts::irq::{self, HandlerId, RegisterError};
+#[cfg(test)]
+use alloc::string::ToString;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+/// IRQ line of the periodic interrupt
+pub const IRQ: u8 = 8;
+/// Frequency of the RTC oscillator, in Hz
+pub const BASE_FREQUENCY: u32 = 32_768;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+/// Not standard, but present on QEMU and most PCs
+const CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Setting this in the index keeps NMIs disabled, which we don't want
+const NMI_DISABLE: u8 = 1 << 7;
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register & !NMI_DISABLE);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register & !NMI_DISABLE);
+            self.data.write(value);
+        }
+    }
+
+    fn read_raw(&mut self) -> RawTime {
+        while self.read(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        RawTime {
+            second: self.read(SECONDS),
+            minute: self.read(MINUTES),
+            hour: self.read(HOURS),
+            day: self.read(DAY),
+            month: self.read(MONTH),
+            year: self.read(YEAR),
+            century: self.read(CENTURY),
+        }
+    }
+}
+
+/// CMOS registers are accessed through an index port, so every access must hold this lock, with
+/// interrupts disabled since the IRQ 8 handler takes it too.
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+
+/// Periodic interrupts received
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_HANDLER: Mutex<Option<HandlerId>> = Mutex::new(None);
+/// Set while the periodic interrupt drives the timer, see `start_timer`
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Time registers as read, before decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// A date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    /// Full year, e.g. 2024
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    /// 0 to 23
+    pub hour: u8,
+    /// 0 to 59
+    pub minute: u8,
+    /// 0 to 59
+    pub second: u8,
+    /// 0 to 999 999 999
+    pub nanosecond: u32,
+}
+
+impl DateTime {
+    /// The date and time `nanos` nanoseconds after the Unix epoch
+    pub fn from_unix_nanos(nanos: u64) -> Self {
+        let seconds = nanos / 1_000_000_000;
+        let days = (seconds / 86_400) as i64;
+        let time = seconds % 86_400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            nanosecond: (nanos % 1_000_000_000) as u32,
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch, which must not be later than this date
+    pub fn unix_nanos(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), self.month, self.day);
+        let seconds = days as u64 * 86_400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second);
+        seconds * 1_000_000_000 + u64::from(self.nanosecond)
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// ISO 8601, e.g. `2024-03-09T17:04:05Z`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, see
+/// <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Decode the time registers according to the format selected in status register B.
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let number = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = number(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match number(raw.century) {
+        century @ 19..=99 => u16::from(century),
+        // no century register, assume the 21st
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(number(raw.year)),
+        month: number(raw.month),
+        day: number(raw.day),
+        hour,
+        minute: number(raw.minute),
+        second: number(raw.second),
+        nanosecond: 0,
+    }
+}
+
+/// Read the current date and time from the RTC.
+///
+/// An update of the registers can't be detected while they are being read, so they are read
+/// until two consecutive readings agree.
+pub fn read() -> DateTime {
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let mut raw = cmos.read_raw();
+        loop {
+            let again = cmos.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        decode(raw, cmos.read(STATUS_B))
+    })
+}
+
+/// Rate divider of register A giving the frequency closest to `hz`.
+///
+/// The frequency is `32768 >> (rate - 1)`, and only rates 3 (8192 Hz) to 15 (2 Hz) are usable.
+fn rate_for(hz: u32) -> u8 {
+    let mut rate = 3;
+    while rate < 15 && BASE_FREQUENCY >> rate >= hz {
+        rate += 1;
+    }
+    rate
+}
+
+/// Start the periodic interrupt on IRQ 8 at (approximately) `hz` times per second.
+///
+/// Returns the frequency actually obtained, a power of two from 2 to 8192 Hz.
+pub fn enable_periodic(hz: u32) -> Result<u32, RegisterError> {
+    let rate = rate_for(hz);
+
+    let mut handler = PERIODIC_HANDLER.lock();
+    if handler.is_none() {
+        *handler = Some(irq::register_irq(IRQ, periodic_interrupt)?);
+    }
+
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & 0xF0) | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        // a pending interrupt would keep IRQ 8 from firing again
+        cmos.read(STATUS_C);
+    });
+    Ok(BASE_FREQUENCY >> (rate - 1))
+}
+
+/// Stop the periodic interrupt.
+pub fn disable_periodic() {
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+        cmos.read(STATUS_C);
+    });
+    if let Some(id) = PERIODIC_HANDLER.lock().take() {
+        irq::unregister(id);
+    }
+}
+
+/// Number of periodic interrupts received since boot
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Drive the timer with the periodic interrupt at (approximately) `hz`, in place of the PIT.
+///
+/// Every interrupt goes through the regular timer handler and advances the tick count by the
+/// period, until `stop_timer` gives the timer back to the PIT. Returns the frequency actually
+/// obtained, as `enable_periodic`.
+pub fn start_timer(hz: u32) -> Result<u32, RegisterError> {
+    let frequency = enable_periodic(hz)?;
+    without_interrupts(|| {
+        irq::mask(super::pit::IRQ);
+        super::set_tick_period(1_000_000_000 / u64::from(frequency));
+        TIMER_RUNNING.store(true, Ordering::Relaxed);
+    });
+    Ok(frequency)
+}
+
+/// Stop the periodic interrupt and give the timer back to the PIT.
+pub fn stop_timer() {
+    without_interrupts(|| {
+        TIMER_RUNNING.store(false, Ordering::Relaxed);
+        super::set_tick_period(super::pit::period_ns());
+        irq::unmask(super::pit::IRQ);
+    });
+    disable_periodic();
+}
+
+/// Whether the periodic interrupt currently drives the timer
+pub fn timer_running() -> bool {
+    TIMER_RUNNING.load(Ordering::Relaxed)
+}
+
+fn periodic_interrupt() {
+    // until register C is read, the RTC raises no further interrupt
+    CMOS.lock().read(STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    if timer_running() {
+        crate::interrupts::timer_interrupt_handler();
+    }
+}
+
+/// BCD and 12 hour formats are decoded
+#[test_case]
+fn test_decode() {
+    let raw = RawTime {
+        second: 0x59,
+        minute: 0x07,
+        hour: 0x12,
+        day: 0x29,
+        month: 0x02,
+        year: 0x24,
+        century: 0x20,
+    };
+    let expected = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 12,
+        minute: 7,
+        second: 59,
+        nanosecond: 0,
+    };
+    assert_eq!(decode(raw, STATUS_B_24_HOUR), expected);
+
+    // 12 AM is midnight, 12 PM is noon
+    assert_eq!(decode(raw, 0).hour, 0);
+    let pm = RawTime {
+        hour: 0x12 | HOUR_PM,
+        ..raw
+    };
+    assert_eq!(decode(pm, 0).hour, 12);
+    let pm = RawTime {
+        hour: 0x01 | HOUR_PM,
+        ..raw
+    };
+    assert_eq!(decode(pm, 0).hour, 13);
+
+    let binary = RawTime {
+        second: 59,
+        minute: 7,
+        hour: 12,
+        day: 29,
+        month: 2,
+        year: 24,
+        century: 0,
+    };
+    assert_eq!(decode(binary, STATUS_B_BINARY | STATUS_B_24_HOUR), expected);
+}
+
+/// Conversions to and from Unix time agree
+#[test_case]
+fn test_unix_time() {
+    assert_eq!(
+        DateTime::from_unix_nanos(0).to_string(),
+        "1970-01-01T00:00:00Z"
+    );
+    let leap_day = DateTime::from_unix_nanos(1_709_164_800_000_000_123);
+    assert_eq!(leap_day.to_string(), "2024-02-29T00:00:00Z");
+    assert_eq!(leap_day.nanosecond, 123);
+    assert_eq!(leap_day.unix_nanos(), 1_709_164_800_000_000_123);
+    let end_of_year = DateTime::from_unix_nanos(4_102_444_799_000_000_000);
+    assert_eq!(end_of_year.to_string(), "2099-12-31T23:59:59Z");
+}
+
+/// The periodic interrupt arrives on IRQ 8
+#[test_case]
+fn test_periodic_interrupt() {
+    assert_eq!(rate_for(8192), 3);
+    assert_eq!(rate_for(1024), 6);
+    assert_eq!(rate_for(1), 15);
+
+    let frequency = enable_periodic(1024).expect("failed to register handler");
+    assert_eq!(frequency, 1024);
+    let ticks = periodic_ticks();
+    while periodic_ticks() < ticks + 2 {
+        x86_64::instructions::hlt();
+    }
+    disable_periodic();
+}
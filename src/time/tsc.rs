@@ -0,0 +1,155 @@
+//! Time stamp counter, calibrated at boot to give nanosecond timestamps.
+//!
+//! The TSC is only used when CPUID reports it as invariant, i.e. ticking at a constant rate
+//! regardless of frequency scaling and sleep states. Otherwise the clock keeps the resolution of
+//! the timer interrupt.
+
+use super::{hpet, pit};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Length of one calibration run, in PIT cycles (10ms)
+const CALIBRATION_CYCLES: u16 = 11_932;
+/// Length of one calibration run against the HPET
+const CALIBRATION_NS: u64 = 10_000_000;
+/// The fastest of this many runs is kept, the others may have been stretched by the hypervisor
+const CALIBRATION_RUNS: usize = 3;
+
+/// Set once the fields below are valid
+static CALIBRATED: AtomicBool = AtomicBool::new(false);
+/// TSC increments per second
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds per TSC increment, as a 32.32 fixed point number
+static NS_PER_CYCLE: AtomicU64 = AtomicU64::new(0);
+/// TSC value and clock reading at the end of calibration, from which later readings are counted
+static BASE_CYCLES: AtomicU64 = AtomicU64::new(0);
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Current value of the time stamp counter
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether the CPU has a TSC running at a constant rate in every power state
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Measure the TSC frequency and start using it as the clock.
+///
+/// The HPET is the reference when it is enabled, the PIT otherwise. Calibrating again later
+/// keeps the clock continuous. Returns the frequency in Hz, or `None` if the TSC is not invariant
+/// and therefore unusable.
+pub(super) fn calibrate() -> Option<u64> {
+    if !is_invariant() {
+        return None;
+    }
+
+    // a run stretched by the hypervisor or SMIs yields a lower frequency, keep the highest one
+    let mut frequency = 0;
+    for _ in 0..CALIBRATION_RUNS {
+        let (cycles, ns) = if hpet::is_enabled() {
+            measure_against_hpet()
+        } else {
+            measure_against_pit()
+        };
+        let run = u128::from(cycles) * 1_000_000_000 / u128::from(ns.max(1));
+        frequency = frequency.max(u64::try_from(run).ok()?);
+    }
+    if frequency == 0 {
+        return None;
+    }
+
+    without_interrupts(|| {
+        // read the clock before switching it over, so it doesn't jump
+        let now_ns = super::now_ns();
+        let now_cycles = read();
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+        NS_PER_CYCLE.store((1_000_000_000 << 32) / frequency, Ordering::Relaxed);
+        BASE_NS.store(now_ns, Ordering::Relaxed);
+        BASE_CYCLES.store(now_cycles, Ordering::Relaxed);
+        CALIBRATED.store(true, Ordering::Release);
+    });
+    Some(frequency)
+}
+
+/// TSC cycles and nanoseconds elapsed during a PIT countdown
+fn measure_against_pit() -> (u64, u64) {
+    let mut start = 0;
+    let mut end = 0;
+    pit::wait_cycles(CALIBRATION_CYCLES, || start = read(), || end = read());
+    let ns = u64::from(CALIBRATION_CYCLES) * 1_000_000_000 / u64::from(pit::BASE_FREQUENCY);
+    (end - start, ns)
+}
+
+/// TSC cycles and nanoseconds elapsed while spinning on the HPET counter
+fn measure_against_hpet() -> (u64, u64) {
+    without_interrupts(|| {
+        let counter_start = hpet::counter().unwrap_or(0);
+        let start = read();
+        loop {
+            let counter = hpet::counter().unwrap_or(0);
+            let end = read();
+            let ns = hpet::ticks_to_ns(counter.wrapping_sub(counter_start));
+            if ns >= CALIBRATION_NS {
+                return (end - start, ns);
+            }
+            core::hint::spin_loop();
+        }
+    })
+}
+
+/// Calibrated TSC frequency in Hz, or `None` if the TSC is not used as a clock
+pub fn frequency() -> Option<u64> {
+    if CALIBRATED.load(Ordering::Acquire) {
+        Some(FREQUENCY.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// Clock reading in nanoseconds, or `None` if the TSC is not used as a clock
+pub(super) fn now_ns() -> Option<u64> {
+    if !CALIBRATED.load(Ordering::Acquire) {
+        return None;
+    }
+    let cycles = read().saturating_sub(BASE_CYCLES.load(Ordering::Relaxed));
+    Some(
+        BASE_NS.load(Ordering::Relaxed)
+            + cycles_to_ns(cycles, NS_PER_CYCLE.load(Ordering::Relaxed)),
+    )
+}
+
+/// Time taken by `cycles` TSC increments, or `None` if the TSC is not used as a clock
+pub fn cycles_to_duration(cycles: u64) -> Option<Duration> {
+    if !CALIBRATED.load(Ordering::Acquire) {
+        return None;
+    }
+    let ns = cycles_to_ns(cycles, NS_PER_CYCLE.load(Ordering::Relaxed));
+    Some(Duration::from_nanos(ns))
+}
+
+fn cycles_to_ns(cycles: u64, ns_per_cycle: u64) -> u64 {
+    ((u128::from(cycles) * u128::from(ns_per_cycle)) >> 32) as u64
+}
+
+/// Fixed point conversion from cycles to nanoseconds
+#[test_case]
+fn test_cycles_to_ns() {
+    // 2 GHz, half a nanosecond per cycle
+    let ns_per_cycle = (1_000_000_000 << 32) / 2_000_000_000;
+    assert_eq!(cycles_to_ns(0, ns_per_cycle), 0);
+    assert_eq!(cycles_to_ns(2_000_000_000, ns_per_cycle), 1_000_000_000);
+    // a day worth of cycles doesn't overflow
+    assert_eq!(
+        cycles_to_ns(2_000_000_000 * 86_400, ns_per_cycle),
+        86_400 * 1_000_000_000
+    );
+}
@@ -7,11 +7,10 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
 
+/// This is a color.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-
-/// This is a color.
 #[allow(missing_docs)]
 pub enum Color {
     Black = 0,
@@ -160,6 +159,7 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Printing more lines than the screen holds scrolls without panicking
 #[test_case]
 fn test_println_many() {
     for i in 0..200 {
@@ -167,6 +167,7 @@ fn test_println_many() {
     }
 }
 
+/// Printed text ends up in the VGA buffer
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
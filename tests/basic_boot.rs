@@ -11,7 +11,7 @@ use rust_os::println;
 pub extern "C" fn _start() -> ! {
     test_main();
 
-    loop {}
+    rust_os::hlt_loop()
 }
 
 #[panic_handler]
@@ -23,10 +23,11 @@ fn main(boot_info: &'static BootInfo) -> ! {
     rust_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    rust_os::symbols::init(boot_info);
     let mut frame_allocator = unsafe { BootinfoFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
     test_main();
-    loop {}
+    rust_os::hlt_loop()
 }
 
 #[test_case]
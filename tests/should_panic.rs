@@ -10,7 +10,7 @@ pub extern "C" fn _start() -> ! {
     serial_println!("[test did not panic]");
     exit_qemu(QemuExitCode::Failed);
 
-    loop {}
+    rust_os::hlt_loop()
 }
 
 fn should_fail() {
@@ -22,5 +22,5 @@ fn should_fail() {
 fn panic(_info: &PanicInfo) -> ! {
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
-    loop {}
+    rust_os::hlt_loop()
 }
@@ -16,7 +16,7 @@ extern "x86-interrupt" fn test_double_fault_handler(
 ) -> ! {
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
-    loop {}
+    rust_os::hlt_loop()
 }
 
 lazy_static! {